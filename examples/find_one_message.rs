@@ -1,5 +1,3 @@
-#[allow(unused_imports)]
-#[allow(dead_code)]
 extern crate imap;
 extern crate securestore;
 // use std::collections::HashSet;
@@ -9,14 +7,7 @@ use std::process::exit;
 // cli
 use clap::Parser;
 
-#[path = "../src/config.rs"]
-mod config;
-#[path = "../src/imap_tools.rs"]
-mod imap_tools;
-#[path = "../src/rules.rs"]
-mod rules;
-
-use clap;
+use imap_sorter::config;
 
 #[derive(Parser, Default, Debug)]
 #[clap(
@@ -109,7 +100,7 @@ fn main() {
     imap_session.select("INBOX").unwrap();
 
     let search_set = imap_session.search(args.filter).expect("search failed");
-    if search_set.len() == 0 {
+    if search_set.is_empty() {
         println!("no message found");
         exit(-1);
     }
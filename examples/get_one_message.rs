@@ -6,14 +6,7 @@ use std::path::Path;
 // cli
 use clap::Parser;
 
-#[path = "../src/config.rs"]
-mod config;
-#[path = "../src/imap_tools.rs"]
-mod imap_tools;
-#[path = "../src/rules.rs"]
-mod rules;
-
-use clap;
+use imap_sorter::config;
 
 #[derive(Parser, Default, Debug)]
 #[clap(
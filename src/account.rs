@@ -0,0 +1,256 @@
+use crate::archive::known_message_ids;
+use crate::config::Configuration;
+use crate::imap_tools::header_value;
+use crate::sieve::quoted;
+use crate::store::{MailStore, StoredMessage};
+use anyhow::Context;
+use imap::types::Flag;
+use imap::ImapConnection;
+use imap_proto::types::ResponseCode;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub type Session = imap::Session<Box<dyn ImapConnection>>;
+
+// messages copied at once to another account
+const BATCH_SIZE: usize = 50;
+
+// server, port, username and password of the main account, or of a named account,
+// using parameter from vault (config.json) if exsit if not try config.ini
+pub fn credentials(
+    config: &Configuration,
+    account: Option<&str>,
+) -> anyhow::Result<(String, u16, String, String)> {
+    // connect to secret manager
+    let key_file = Path::new(config.key_path.as_str());
+    let secret_manager = securestore::SecretsManager::load(
+        config.secure_store_path.as_str(),
+        securestore::KeySource::File(key_file),
+    )
+    .with_context(|| {
+        format!(
+            "Failed to load SecureStore vault {}",
+            config.secure_store_path
+        )
+    })?;
+
+    // secrets of other accounts are prefixed with their name, like archive.imap_password
+    let (prefix, server, port, username, password) = match account {
+        None => (
+            String::new(),
+            &config.imap_server,
+            config.imap_port,
+            &config.imap_username,
+            &config.imap_password,
+        ),
+        Some(name) => {
+            let account = config
+                .accounts
+                .get(name)
+                .with_context(|| format!("no account {} in configuration", name))?;
+            (
+                format!("{}.", name),
+                &account.imap_server,
+                account.imap_port,
+                &account.imap_username,
+                &account.imap_password,
+            )
+        }
+    };
+    let secret = |key: &str, default: &String| {
+        secret_manager
+            .get(&format!("{}{}", prefix, key))
+            .unwrap_or(default.clone())
+    };
+    Ok((
+        secret("imap_server", server),
+        port,
+        secret("imap_username", username),
+        secret("imap_password", password),
+    ))
+}
+
+// connect and log into the IMAP server of the main account, or of a named account
+pub fn connect(config: &Configuration, account: Option<&str>) -> anyhow::Result<Session> {
+    let (domain, port, username, password) = credentials(config, account)?;
+    let domain = domain.as_str();
+
+    // we pass in the domain twice to check that the server's TLS
+    // certificate is valid for the domain we're connecting to.
+    let client = imap::ClientBuilder::new(domain, port)
+        .connect()
+        .map_err(|error| anyhow::anyhow!("Error with IMAP server : {}", error))?;
+
+    // the client we have here is unauthenticated.
+    // to do anything useful with the e-mails, we need to log in
+    let imap_session = client
+        .login(username, password)
+        .map_err(|e| anyhow::anyhow!("cannot connect to IMAP server : {}", e.0))?;
+    Ok(imap_session)
+}
+
+// sessions on the other accounts, opened the first time a rule moves messages there
+pub struct Accounts<'a> {
+    config: &'a Configuration,
+    sessions: RefCell<BTreeMap<String, Session>>,
+}
+
+impl<'a> Accounts<'a> {
+    pub fn new(config: &'a Configuration) -> Self {
+        Accounts {
+            config,
+            sessions: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn with_session<T>(
+        &self,
+        name: &str,
+        action: impl FnOnce(&mut Session) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut sessions = self.sessions.borrow_mut();
+        if !sessions.contains_key(name) {
+            log::debug!("connecting to account {}", name);
+            sessions.insert(name.to_string(), connect(self.config, Some(name))?);
+        }
+        action(sessions.get_mut(name).expect("session just opened"))
+    }
+
+    // be nice to the other servers too
+    pub fn logout(&self) {
+        for (name, mut session) in std::mem::take(&mut *self.sessions.borrow_mut()) {
+            if let Err(error) = session.logout() {
+                log::warn!("failed to logout from account {}: {}", name, error);
+            }
+        }
+    }
+}
+
+// append a message, creating the folder when the server tells us it does not exist
+fn append_or_create(
+    session: &mut Session,
+    folder: &str,
+    body: &[u8],
+    flags: &[Flag],
+    date: Option<chrono::DateTime<chrono::FixedOffset>>,
+) -> imap::error::Result<()> {
+    for attempt in 0..2 {
+        let mut append = session.append(folder, body);
+        append.flags(flags.iter().filter(|flag| **flag != Flag::Recent).cloned());
+        if let Some(date) = date {
+            append.internal_date(date);
+        }
+        match append.finish() {
+            Err(imap::Error::No(no))
+                if no.code == Some(ResponseCode::TryCreate) && attempt == 0 =>
+            {
+                log::info!("creating folder {}", folder);
+                session.create(folder)?;
+            }
+            result => return result.map(|_| ()),
+        }
+    }
+    Ok(())
+}
+
+// append messages to a folder of another account, skipping those a previous run already copied,
+// the messages found there afterwards are added to `verified`, even when an append failed
+fn copy_messages(
+    destination: &mut Session,
+    account: &str,
+    target: &str,
+    messages: &[StoredMessage],
+    verified: &mut Vec<(u32, String)>,
+) -> anyhow::Result<()> {
+    let mut known = known_message_ids(destination, &[target.to_string()])?;
+    let mut copied = Vec::new();
+    let mut failure = None;
+    for message in messages {
+        let (uid, body) = (message.uid, message.body.as_slice());
+        let header_end = body
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap_or(body.len());
+        let Some(message_id) = header_value(&body[..header_end], "Message-ID") else {
+            log::warn!(
+                "message {} has no Message-ID to verify its copy, not moved",
+                uid
+            );
+            continue;
+        };
+        if known.insert(message_id.clone()) {
+            let flags: Vec<Flag> = message
+                .flags
+                .iter()
+                .map(|flag| Flag::from(flag.as_str()))
+                .collect();
+            if let Err(error) =
+                append_or_create(destination, target, body, &flags, message.internal_date)
+            {
+                failure = Some(error.into());
+                break;
+            }
+        } else {
+            log::debug!(
+                "{} already in {}:{}, not copied",
+                message_id,
+                account,
+                target
+            );
+        }
+        copied.push((uid, message_id));
+    }
+    if copied.is_empty() {
+        return failure.map_or(Ok(()), Err);
+    }
+
+    // only what made it to the other account is deleted here
+    destination.examine(target)?;
+    for (uid, message_id) in copied {
+        let found = destination.uid_search(format!("HEADER Message-ID {}", quoted(&message_id)))?;
+        if found.is_empty() {
+            log::warn!(
+                "{} not found in {}:{}, not deleted",
+                message_id,
+                account,
+                target
+            );
+        } else {
+            verified.push((uid, message_id));
+        }
+    }
+    failure.map_or(Ok(()), Err)
+}
+
+// copy messages of the opened folder to a folder of another account, and delete them once
+// their Message-ID is found there, the uids and Message-IDs of the messages moved are added
+// to `moved` even when a later batch fails
+pub(crate) fn move_to_account(
+    store: &mut dyn MailStore,
+    accounts: &Accounts,
+    account: &str,
+    target: &str,
+    uids: &[u32],
+    moved: &mut Vec<(u32, String)>,
+) -> anyhow::Result<()> {
+    // copies that cannot be deleted here would be made again at each run
+    if !store.can_delete()? {
+        anyhow::bail!(
+            "server has no UIDPLUS, {} messages not moved to leave other deleted messages alone",
+            uids.len()
+        );
+    }
+    for batch in uids.chunks(BATCH_SIZE) {
+        let messages = store.fetch_messages(batch)?;
+        let mut verified = Vec::new();
+        let copied = accounts.with_session(account, |destination| {
+            copy_messages(destination, account, target, &messages, &mut verified)
+        });
+        let verified_uids: Vec<u32> = verified.iter().map(|(uid, _)| *uid).collect();
+        store.delete_messages(&verified_uids)?;
+        moved.extend(verified);
+        copied?;
+    }
+    Ok(())
+}
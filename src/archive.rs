@@ -0,0 +1,679 @@
+use crate::imap_tools::{
+    apply_rules_on_folder, fetch_message_ids, header_value, record_journal_entry, uid_set,
+    RunOptions,
+};
+use crate::journal::JournalEntry;
+use crate::rules::Rule;
+use crate::tags::TagExpression;
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset};
+use imap::types::Flag;
+use imap::ImapConnection;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+// messages fetched at once when exporting a folder
+const BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum ArchiveFormat {
+    // one file, messages separated by From lines (mboxrd)
+    #[default]
+    Mbox,
+    // directory with cur, new and tmp, flags in file names
+    Maildir,
+    // directory with one .eml file per message
+    Eml,
+}
+
+// a message as exported, with what IMAP knows about it
+pub(crate) struct ArchivedMessage<'a> {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub internal_date: Option<DateTime<FixedOffset>>,
+    pub body: &'a [u8],
+}
+
+// Maildir info letters, in ASCII order as the format requires
+pub(crate) fn maildir_info(flags: &[String]) -> String {
+    let mut letters: Vec<char> = flags
+        .iter()
+        .filter_map(|flag| match flag.as_str() {
+            "\\Draft" => Some('D'),
+            "\\Flagged" => Some('F'),
+            "\\Answered" => Some('R'),
+            "\\Seen" => Some('S'),
+            "\\Deleted" => Some('T'),
+            _ => None,
+        })
+        .collect();
+    letters.sort();
+    letters.into_iter().collect()
+}
+
+// Status, X-Status and X-Keywords headers mail clients read flags from in mbox files
+pub(crate) fn mbox_flag_headers(flags: &[String]) -> String {
+    let mut status = String::new();
+    let mut x_status = String::new();
+    let mut keywords = Vec::new();
+    for flag in flags {
+        match flag.as_str() {
+            "\\Seen" => status.push('R'),
+            "\\Answered" => x_status.push('A'),
+            "\\Flagged" => x_status.push('F'),
+            "\\Draft" => x_status.push('T'),
+            "\\Deleted" => x_status.push('D'),
+            flag if !flag.starts_with('\\') => keywords.push(flag.to_string()),
+            _ => {}
+        }
+    }
+    status.push('O');
+    let mut headers = format!("Status: {}\n", status);
+    if !x_status.is_empty() {
+        headers.push_str(&format!("X-Status: {}\n", x_status));
+    }
+    if !keywords.is_empty() {
+        headers.push_str(&format!("X-Keywords: {}\n", keywords.join(" ")));
+    }
+    headers
+}
+
+// write messages in one of the archive formats
+pub(crate) struct ArchiveWriter {
+    format: ArchiveFormat,
+    path: String,
+    mbox: Option<BufWriter<File>>,
+    uid_validity: u32,
+}
+
+impl ArchiveWriter {
+    pub fn create(format: ArchiveFormat, path: &str, uid_validity: u32) -> Result<ArchiveWriter> {
+        let mbox = match format {
+            ArchiveFormat::Mbox => Some(BufWriter::new(
+                File::create(path).with_context(|| format!("cannot create {}", path))?,
+            )),
+            ArchiveFormat::Maildir => {
+                for sub_folder in ["cur", "new", "tmp"] {
+                    fs::create_dir_all(Path::new(path).join(sub_folder))
+                        .with_context(|| format!("cannot create Maildir {}", path))?;
+                }
+                None
+            }
+            ArchiveFormat::Eml => {
+                fs::create_dir_all(path).with_context(|| format!("cannot create {}", path))?;
+                None
+            }
+        };
+        Ok(ArchiveWriter {
+            format,
+            path: path.to_string(),
+            mbox,
+            uid_validity,
+        })
+    }
+
+    pub fn write(&mut self, message: &ArchivedMessage) -> Result<()> {
+        match self.format {
+            ArchiveFormat::Mbox => self.write_mbox(message),
+            ArchiveFormat::Maildir => {
+                let seconds = message.internal_date.map_or(0, |date| date.timestamp());
+                let file_name = format!(
+                    "{}.{}_{}.imap_sorter:2,{}",
+                    seconds,
+                    self.uid_validity,
+                    message.uid,
+                    maildir_info(&message.flags)
+                );
+                let path = Path::new(&self.path).join("cur").join(file_name);
+                write_file(&path, message)
+            }
+            ArchiveFormat::Eml => {
+                let path = Path::new(&self.path).join(format!("{}.eml", message.uid));
+                write_file(&path, message)
+            }
+        }
+    }
+
+    fn write_mbox(&mut self, message: &ArchivedMessage) -> Result<()> {
+        let Some(mbox) = &mut self.mbox else {
+            return Ok(());
+        };
+        let date = message
+            .internal_date
+            .map(|date| date.naive_utc().format("%a %b %e %H:%M:%S %Y").to_string())
+            .unwrap_or("Thu Jan  1 00:00:00 1970".to_string());
+        writeln!(mbox, "From MAILER-DAEMON {}", date)?;
+        mbox.write_all(mbox_flag_headers(&message.flags).as_bytes())?;
+        // bytes as they are, 8-bit messages must come back unchanged
+        let mut lines: Vec<&[u8]> = message.body.split(|byte| *byte == b'\n').collect();
+        if lines.last().is_some_and(|line| line.is_empty()) {
+            lines.pop();
+        }
+        for line in lines {
+            // mboxrd quoting, unquoted when read back
+            let unquoted = line
+                .iter()
+                .position(|byte| *byte != b'>')
+                .map_or(&[][..], |start| &line[start..]);
+            if unquoted.starts_with(b"From ") {
+                mbox.write_all(b">")?;
+            }
+            mbox.write_all(line)?;
+            mbox.write_all(b"\n")?;
+        }
+        mbox.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if let Some(mut mbox) = self.mbox {
+            mbox.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// one message per file, dated with its INTERNALDATE
+fn write_file(path: &Path, message: &ArchivedMessage) -> Result<()> {
+    let mut file =
+        File::create(path).with_context(|| format!("cannot create {}", path.display()))?;
+    file.write_all(message.body)?;
+    if let Some(date) = message.internal_date {
+        file.set_modified(SystemTime::from(date))?;
+    }
+    Ok(())
+}
+
+// export the messages of a folder matching a search filter, returns how many were written
+pub fn export_folder(
+    imap_session: &mut imap::Session<Box<dyn ImapConnection>>,
+    folder: &str,
+    filter: &str,
+    format: ArchiveFormat,
+    path: &str,
+) -> Result<usize> {
+    // read only, exporting must not change flags
+    let mailbox = imap_session.examine(folder)?;
+    let mut uids: Vec<u32> = imap_session.uid_search(filter)?.into_iter().collect();
+    uids.sort();
+    log::info!(
+        "exporting {} messages of {} to {}",
+        uids.len(),
+        folder,
+        path
+    );
+
+    let mut writer = ArchiveWriter::create(format, path, mailbox.uid_validity.unwrap_or(0))?;
+    let mut count = 0;
+    for batch in uids.chunks(BATCH_SIZE) {
+        let messages =
+            imap_session.uid_fetch(uid_set(batch), "(UID FLAGS INTERNALDATE BODY.PEEK[])")?;
+        for message in messages.iter() {
+            let (Some(uid), Some(body)) = (message.uid, message.body()) else {
+                continue;
+            };
+            let flags = message
+                .flags()
+                .iter()
+                .filter(|flag| **flag != Flag::Recent)
+                .map(|flag| flag.to_string())
+                .collect();
+            writer.write(&ArchivedMessage {
+                uid,
+                flags,
+                internal_date: message.internal_date(),
+                body,
+            })?;
+            count += 1;
+        }
+        log::debug!("{} messages exported", count);
+    }
+    writer.finish()?;
+    Ok(count)
+}
+
+// a message read back from an archive, to be appended to a folder
+pub(crate) struct LocalMessage {
+    // position in the archive, recorded to resume an interrupted restore
+    pub key: String,
+    pub flags: Vec<String>,
+    pub internal_date: Option<DateTime<FixedOffset>>,
+    pub body: Vec<u8>,
+}
+
+// IMAP flags of Maildir info letters
+pub(crate) fn maildir_flags(info: &str) -> Vec<String> {
+    info.chars()
+        .filter_map(|letter| match letter {
+            'D' => Some("\\Draft"),
+            'F' => Some("\\Flagged"),
+            'R' => Some("\\Answered"),
+            'S' => Some("\\Seen"),
+            'T' => Some("\\Deleted"),
+            _ => None,
+        })
+        .map(|flag| flag.to_string())
+        .collect()
+}
+
+// IMAP flags of a Status, X-Status or X-Keywords header of mbox files
+fn mbox_header_flags(name: &str, value: &str) -> Vec<String> {
+    if name.eq_ignore_ascii_case("X-Keywords") {
+        return value.split_whitespace().map(|k| k.to_string()).collect();
+    }
+    value
+        .trim()
+        .chars()
+        .filter_map(
+            |letter| match (name.eq_ignore_ascii_case("Status"), letter) {
+                (true, 'R') => Some("\\Seen"),
+                (false, 'A') => Some("\\Answered"),
+                (false, 'F') => Some("\\Flagged"),
+                (false, 'T') => Some("\\Draft"),
+                (false, 'D') => Some("\\Deleted"),
+                _ => None,
+            },
+        )
+        .map(|flag| flag.to_string())
+        .collect()
+}
+
+// messages use CRLF line endings over IMAP
+fn to_crlf(lines: &[&[u8]]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for line in lines {
+        body.extend_from_slice(line.strip_suffix(b"\r").unwrap_or(line));
+        body.extend_from_slice(b"\r\n");
+    }
+    body
+}
+
+// date of a From line, like: From MAILER-DAEMON Thu Jan  1 00:00:00 1970
+fn mbox_date(from_line: &str) -> Option<DateTime<FixedOffset>> {
+    let date: Vec<&str> = from_line.split_whitespace().skip(2).collect();
+    chrono::NaiveDateTime::parse_from_str(&date.join(" "), "%a %b %e %H:%M:%S %Y")
+        .ok()
+        .map(|date| date.and_utc().fixed_offset())
+}
+
+// messages of an mbox file, read one at a time
+pub(crate) struct MboxMessages {
+    reader: BufReader<File>,
+    // From line of the next message, met at the end of the previous one
+    next_from: Option<Vec<u8>>,
+    number: usize,
+}
+
+impl MboxMessages {
+    // lines up to the next From line that follows an empty line, or up to the end of the file
+    fn read_until_from(&mut self, mut after_blank: bool) -> Result<Vec<Vec<u8>>> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                return Ok(lines);
+            }
+            if line.ends_with(b"\n") {
+                line.pop();
+            }
+            if after_blank && line.starts_with(b"From ") {
+                self.next_from = Some(line);
+                return Ok(lines);
+            }
+            after_blank = line.strip_suffix(b"\r").unwrap_or(&line).is_empty();
+            lines.push(line);
+        }
+    }
+}
+
+impl Iterator for MboxMessages {
+    type Item = Result<LocalMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let from_line = self.next_from.take()?;
+        let message = self
+            .read_until_from(false)
+            .map(|lines| mbox_message(self.number, &from_line, lines));
+        self.number += 1;
+        Some(message)
+    }
+}
+
+// a message of an mbox file, from the lines following its From line
+fn mbox_message(number: usize, from_line: &[u8], mut lines: Vec<Vec<u8>>) -> LocalMessage {
+    // the empty line before the next From line is not part of the message
+    if lines
+        .last()
+        .is_some_and(|line| line.strip_suffix(b"\r").unwrap_or(line).is_empty())
+    {
+        lines.pop();
+    }
+    let mut flags = Vec::new();
+    let mut in_header = true;
+    let mut kept: Vec<Vec<u8>> = Vec::new();
+    for line in lines {
+        let text = String::from_utf8_lossy(&line);
+        if in_header && text.trim_end().is_empty() {
+            in_header = false;
+        }
+        if in_header {
+            if let Some((name, value)) = text.split_once(':') {
+                if ["Status", "X-Status", "X-Keywords"]
+                    .iter()
+                    .any(|header| header.eq_ignore_ascii_case(name))
+                {
+                    flags.extend(mbox_header_flags(name, value));
+                    continue;
+                }
+            }
+        }
+        // mboxrd quoting of From lines
+        if line.starts_with(b">")
+            && line
+                .iter()
+                .skip_while(|byte| **byte == b'>')
+                .take(5)
+                .eq(b"From ".iter())
+        {
+            kept.push(line[1..].to_vec());
+        } else {
+            kept.push(line);
+        }
+    }
+    let kept: Vec<&[u8]> = kept.iter().map(|line| line.as_slice()).collect();
+    LocalMessage {
+        key: format!("{}", number),
+        flags,
+        internal_date: mbox_date(&String::from_utf8_lossy(from_line)),
+        body: to_crlf(&kept),
+    }
+}
+
+pub(crate) fn read_mbox(path: &str) -> Result<MboxMessages> {
+    let file = File::open(path).with_context(|| format!("cannot read {}", path))?;
+    let mut messages = MboxMessages {
+        reader: BufReader::new(file),
+        next_from: None,
+        number: 0,
+    };
+    // what comes before the first From line is not a message
+    messages
+        .read_until_from(true)
+        .with_context(|| format!("cannot read {}", path))?;
+    Ok(messages)
+}
+
+// date of a Maildir or .eml file, its INTERNALDATE when exported
+fn file_date(path: &Path) -> Option<DateTime<FixedOffset>> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(DateTime::<chrono::Utc>::from(modified).fixed_offset())
+}
+
+fn read_file(path: &Path, key: String, flags: Vec<String>) -> Result<LocalMessage> {
+    let content = fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let lines: Vec<&[u8]> = content
+        .strip_suffix(b"\n")
+        .unwrap_or(&content)
+        .split(|byte| *byte == b'\n')
+        .collect();
+    Ok(LocalMessage {
+        key,
+        flags,
+        internal_date: file_date(path),
+        body: to_crlf(&lines),
+    })
+}
+
+// sorted file names of a directory
+fn file_names(path: &Path) -> Result<Vec<String>> {
+    let mut names: Vec<String> = fs::read_dir(path)
+        .with_context(|| format!("cannot read {}", path.display()))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+// messages read one at a time, the files of a directory being listed first
+pub(crate) type LocalMessages = Box<dyn Iterator<Item = Result<LocalMessage>>>;
+
+pub(crate) fn read_maildir(path: &str) -> Result<LocalMessages> {
+    let mut files = Vec::new();
+    for sub_folder in ["cur", "new"] {
+        let folder = Path::new(path).join(sub_folder);
+        for name in file_names(&folder)? {
+            let flags = match name.rsplit_once(":2,") {
+                Some((_, info)) => maildir_flags(info),
+                None => Vec::new(),
+            };
+            let key = format!("{}/{}", sub_folder, name);
+            files.push((folder.join(&name), key, flags));
+        }
+    }
+    Ok(Box::new(
+        files
+            .into_iter()
+            .map(|(file, key, flags)| read_file(&file, key, flags)),
+    ))
+}
+
+pub(crate) fn read_eml(path: &str) -> Result<LocalMessages> {
+    let folder = Path::new(path).to_path_buf();
+    let names = file_names(&folder)?
+        .into_iter()
+        .filter(|name| name.ends_with(".eml"));
+    Ok(Box::new(names.map(move |name| {
+        read_file(&folder.join(&name), name, Vec::new())
+    })))
+}
+
+// messages of an archive, its format told by what the path is
+pub(crate) fn read_archive(path: &str) -> Result<LocalMessages> {
+    let path_ref = Path::new(path);
+    if path_ref.is_file() {
+        Ok(Box::new(read_mbox(path)?))
+    } else if path_ref.join("cur").is_dir() {
+        read_maildir(path)
+    } else if path_ref.is_dir() {
+        read_eml(path)
+    } else {
+        anyhow::bail!("no mbox file, Maildir or directory at {}", path)
+    }
+}
+
+// file next to the archive listing the messages already appended, to resume an interrupted restore
+fn progress_path(path: &str) -> String {
+    format!("{}.restored", path.trim_end_matches('/'))
+}
+
+// Message-IDs already in the folders, missing folders have none
+pub(crate) fn known_message_ids(
+    imap_session: &mut imap::Session<Box<dyn ImapConnection>>,
+    folders: &[String],
+) -> Result<HashSet<String>> {
+    let mut message_ids = HashSet::new();
+    for folder in folders {
+        match imap_session.examine(folder) {
+            Ok(mailbox) if mailbox.exists > 0 => {
+                let uids: Vec<u32> = imap_session.uid_search("ALL")?.into_iter().collect();
+                for (_, message_id) in fetch_message_ids(imap_session, &uids)? {
+                    message_ids.insert(message_id);
+                }
+            }
+            Ok(_) => {}
+            Err(error) => log::debug!("cannot examine {}: {}", folder, error),
+        }
+    }
+    Ok(message_ids)
+}
+
+// append the messages of an archive to a folder, then sort them with the rules of the folder
+pub fn restore_archive(
+    imap_session: &mut imap::Session<Box<dyn ImapConnection>>,
+    path: &str,
+    folder: &str,
+    rules: Vec<Rule>,
+    tag: &Option<TagExpression>,
+    options: &RunOptions,
+) -> Result<usize> {
+    let messages = read_archive(path)?;
+    let progress = progress_path(path);
+    let done: HashSet<String> = fs::read_to_string(&progress)
+        .map(|content| content.lines().map(|key| key.to_string()).collect())
+        .unwrap_or_default();
+    if !done.is_empty() {
+        log::info!("resuming restore, {} messages already appended", done.len());
+    }
+
+    // messages may already be in the folder, or where its rules have sorted them
+    let mut folders = vec![folder.to_string()];
+    for rule in &rules {
+        if !rule.target_uses_list_id() && !folders.contains(&rule.target) {
+            folders.push(rule.target.clone());
+        }
+    }
+    let mut known = known_message_ids(imap_session, &folders)?;
+
+    let mailbox = match imap_session.examine(folder) {
+        Ok(mailbox) => mailbox,
+        Err(_) => {
+            log::info!("creating folder {}", folder);
+            imap_session.create(folder)?;
+            imap_session.examine(folder)?
+        }
+    };
+
+    // only simulating, messages are read and counted without appending them
+    let mut progress_file = if options.acts_on(true) {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&progress)
+            .with_context(|| format!("cannot write {}", progress))?;
+        Some(file)
+    } else {
+        None
+    };
+    let mut entry = JournalEntry::new("append", "restore", path, Some(folder));
+    let mut appended = 0;
+    // counted as they are met, the progress file may list keys of another archive
+    let mut already_there = 0;
+    // each message is appended once read, the archive is never held in memory as a whole
+    for message in messages {
+        let message = message?;
+        if done.contains(&message.key) {
+            continue;
+        }
+        let header_end = message
+            .body
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap_or(message.body.len());
+        let message_id = header_value(&message.body[..header_end], "Message-ID");
+        if let Some(message_id) = &message_id {
+            if !known.insert(message_id.clone()) {
+                log::debug!("{} already in mailbox, skipping", message_id);
+                already_there += 1;
+                continue;
+            }
+        }
+        if let Some(progress_file) = &mut progress_file {
+            let mut append = imap_session.append(folder, &message.body);
+            append.flags(message.flags.iter().map(|flag| Flag::from(flag.as_str())));
+            if let Some(date) = message.internal_date {
+                append.internal_date(date);
+            }
+            append.finish()?;
+            writeln!(progress_file, "{}", message.key)?;
+            entry.message_ids.extend(message_id);
+        }
+        appended += 1;
+    }
+    if progress_file.is_none() {
+        log::info!(
+            "{} messages of {} to restore in {}, {} already there",
+            appended,
+            path,
+            folder,
+            already_there
+        );
+        return Ok(0);
+    }
+    record_journal_entry(options.journal, &entry);
+    log::info!(
+        "{} messages appended to {}, {} already there",
+        appended,
+        folder,
+        already_there
+    );
+
+    // sort only what was just appended, from the first new uid on
+    // with nothing appended, UID uid_next:* would still match the last message of the folder
+    if let (Some(uid_next), true) = (mailbox.uid_next, appended > 0) {
+        let rules = rules
+            .into_iter()
+            .map(|mut rule| {
+                rule.filter = format!("UID {}:* {}", uid_next, rule.filter)
+                    .trim()
+                    .to_string();
+                rule
+            })
+            .collect();
+        apply_rules_on_folder(imap_session, rules, &folder.to_string(), tag, options);
+    }
+    fs::remove_file(&progress)?;
+    Ok(appended)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mbox_messages_are_read_one_at_a_time() {
+        let path = std::env::temp_dir().join(format!("imap_sorter_mbox_{}", std::process::id()));
+        let content = "not a message\n\
+                       \n\
+                       From a@x Thu Jan  4 10:00:00 2024\n\
+                       Status: RO\n\
+                       X-Status: AF\n\
+                       Subject: first\n\
+                       \n\
+                       >From the start\n\
+                       From inside a paragraph\n\
+                       \n\
+                       From b@x Fri Jan  5 11:30:00 2024\r\n\
+                       Subject: second\r\n\
+                       \r\n\
+                       body\r\n";
+        fs::write(&path, content).unwrap();
+        let mut messages = read_mbox(path.to_str().unwrap()).unwrap();
+
+        let first = messages.next().unwrap().unwrap();
+        assert_eq!(first.key, "0");
+        assert_eq!(first.flags, ["\\Seen", "\\Answered", "\\Flagged"]);
+        assert_eq!(
+            first.internal_date.map(|date| date.to_rfc3339()),
+            Some("2024-01-04T10:00:00+00:00".to_string())
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&first.body),
+            "Subject: first\r\n\r\nFrom the start\r\nFrom inside a paragraph\r\n"
+        );
+
+        let second = messages.next().unwrap().unwrap();
+        assert_eq!(second.key, "1");
+        assert!(second.flags.is_empty());
+        assert_eq!(
+            String::from_utf8_lossy(&second.body),
+            "Subject: second\r\n\r\nbody\r\n"
+        );
+        assert!(messages.next().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
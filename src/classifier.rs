@@ -0,0 +1,455 @@
+use crate::imap_tools::{decode_header, header_value};
+use crate::store::MailStore;
+use anyhow::{Context, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+// what is fetched of each message, to train or classify it
+const FETCH_FIELDS: [&str; 4] = [
+    "Subject",
+    "From",
+    "Content-Type",
+    "Content-Transfer-Encoding",
+];
+const FETCH_TEXT_LIMIT: usize = 65536;
+
+// tokens shorter or longer than this are ignored
+const MIN_TOKEN_LEN: usize = 2;
+const MAX_TOKEN_LEN: usize = 30;
+
+// classifier section of the rules file: the model file, and for each class the folders to train on
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ClassifierConfig {
+    pub model: String,
+    pub classes: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct ClassStats {
+    pub documents: u64,
+    pub tokens: u64,
+    pub counts: BTreeMap<String, u64>,
+}
+
+// below this probability of its class, a message is left alone, unless the rule sets min_probability
+pub(crate) const DEFAULT_MIN_PROBABILITY: f64 = 0.9;
+
+// multinomial naive Bayes model, ordered maps keep training and classification deterministic
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct NaiveBayes {
+    pub classes: BTreeMap<String, ClassStats>,
+    // distinct tokens of all classes, kept up to date instead of counted for each message
+    #[serde(skip)]
+    vocabulary: usize,
+}
+
+impl NaiveBayes {
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open model: {}", path))?;
+        let mut model: NaiveBayes = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse model: {}", path))?;
+        let mut vocabulary: Vec<&String> = model
+            .classes
+            .values()
+            .flat_map(|stats| stats.counts.keys())
+            .collect();
+        vocabulary.sort();
+        vocabulary.dedup();
+        model.vocabulary = vocabulary.len();
+        model
+            .check()
+            .with_context(|| format!("Model {} cannot be used, train it again", path))?;
+        Ok(model)
+    }
+
+    // with a single class, every message would be in it for sure
+    pub fn check(&self) -> Result<()> {
+        let trained = self
+            .classes
+            .values()
+            .filter(|stats| stats.documents > 0)
+            .count();
+        if trained < 2 {
+            anyhow::bail!(
+                "the classifier needs messages of at least two classes, it has {}",
+                trained
+            );
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create model: {}", path))?;
+        serde_json::to_writer(BufWriter::new(file), self)
+            .with_context(|| format!("Failed to write model: {}", path))
+    }
+
+    pub fn train(&mut self, class: &str, tokens: &[String]) {
+        for token in tokens {
+            if !self
+                .classes
+                .values()
+                .any(|stats| stats.counts.contains_key(token))
+            {
+                self.vocabulary += 1;
+            }
+            let stats = self.classes.entry(class.to_string()).or_default();
+            stats.tokens += 1;
+            *stats.counts.entry(token.clone()).or_default() += 1;
+        }
+        self.classes.entry(class.to_string()).or_default().documents += 1;
+    }
+
+    // most likely class of a document, with its probability among the trained classes
+    // ties go to the first class in alphabetical order
+    pub fn classify(&self, tokens: &[String]) -> Option<(String, f64)> {
+        let documents: u64 = self.classes.values().map(|stats| stats.documents).sum();
+        if documents == 0 {
+            return None;
+        }
+        let vocabulary = self.vocabulary as f64;
+        let scores: Vec<(&String, f64)> = self
+            .classes
+            .iter()
+            .map(|(class, stats)| {
+                // laplace smoothing, so that an unknown token does not rule a class out
+                let mut score = (stats.documents as f64 / documents as f64).ln();
+                let denominator = stats.tokens as f64 + vocabulary;
+                for token in tokens {
+                    let count = stats.counts.get(token).copied().unwrap_or(0) as f64;
+                    score += ((count + 1.0) / denominator).ln();
+                }
+                (class, score)
+            })
+            .collect();
+        let mut best: Option<(&String, f64)> = None;
+        for (class, score) in &scores {
+            if best.is_none_or(|(_, best)| *score > best) {
+                best = Some((class, *score));
+            }
+        }
+        let (class, best) = best?;
+        // log probabilities back to a probability, relative to the best to stay in range
+        let total: f64 = scores.iter().map(|(_, score)| (score - best).exp()).sum();
+        Some((class.clone(), 1.0 / total))
+    }
+}
+
+fn decode_quoted_printable(text: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] == b'=' {
+            // soft line break
+            if text[i + 1..].starts_with(b"\r\n") {
+                i += 3;
+                continue;
+            }
+            if text[i + 1..].starts_with(b"\n") {
+                i += 2;
+                continue;
+            }
+            if let Some(byte) = text
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                bytes.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        bytes.push(text[i]);
+        i += 1;
+    }
+    bytes
+}
+
+fn strip_html(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => {
+                in_tag = false;
+                text.push(' ');
+            }
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+// parameter of a header value, like the boundary of a Content-Type
+fn header_parameter(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|parameter| {
+        let (key, value) = parameter.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+// readable text of a message body, text parts of multipart messages are decoded
+pub(crate) fn extract_text(header: &[u8], body: &[u8]) -> String {
+    let content_type = header_value(header, "Content-Type")
+        .unwrap_or("text/plain".to_string())
+        .to_lowercase();
+    if content_type.starts_with("multipart/") {
+        let Some(boundary) = header_value(header, "Content-Type")
+            .and_then(|value| header_parameter(&value, "boundary"))
+        else {
+            return String::new();
+        };
+        let body = String::from_utf8_lossy(body);
+        let delimiter = format!("--{}", boundary);
+        return body
+            .split(&delimiter)
+            .skip(1)
+            .filter_map(|part| {
+                // each part has its own header, up to the first empty line
+                let part = part.trim_start_matches(['\r', '\n']);
+                let (part_header, part_body) = part
+                    .split_once("\r\n\r\n")
+                    .or_else(|| part.split_once("\n\n"))?;
+                Some(extract_text(part_header.as_bytes(), part_body.as_bytes()))
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+    }
+    if !content_type.starts_with("text/") {
+        return String::new();
+    }
+    let encoding = header_value(header, "Content-Transfer-Encoding")
+        .unwrap_or_default()
+        .to_lowercase();
+    let decoded = match encoding.as_str() {
+        "base64" => {
+            let compact: Vec<u8> = body
+                .iter()
+                .copied()
+                .filter(|b| !b.is_ascii_whitespace())
+                .collect();
+            base64::engine::general_purpose::STANDARD
+                .decode(compact)
+                .unwrap_or_default()
+        }
+        "quoted-printable" => decode_quoted_printable(body),
+        _ => body.to_vec(),
+    };
+    let text = String::from_utf8_lossy(&decoded).to_string();
+    if content_type.starts_with("text/html") {
+        strip_html(&text)
+    } else {
+        text
+    }
+}
+
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| (MIN_TOKEN_LEN..=MAX_TOKEN_LEN).contains(&word.chars().count()))
+        .map(|word| word.to_lowercase())
+}
+
+// tokens of a message: words of the subject and body, and the sender domain
+pub(crate) fn tokenize(header: &[u8], body: &[u8]) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    if let Some(subject) = header_value(header, "Subject") {
+        let subject = decode_header(subject.as_bytes());
+        tokens.extend(words(&subject).map(|word| format!("subject:{}", word)));
+    }
+    if let Some(from) = header_value(header, "From") {
+        if let Some((_, domain)) = from.trim_end_matches('>').rsplit_once('@') {
+            tokens.push(format!("from:{}", domain.to_lowercase()));
+        }
+    }
+    tokens.extend(words(&extract_text(header, body)));
+    tokens
+}
+
+// fetch and tokenize messages of the selected folder, as a list of (uid, tokens)
+pub(crate) fn fetch_tokens(
+    store: &mut dyn MailStore,
+    uids: &[u32],
+) -> anyhow::Result<Vec<(u32, Vec<String>)>> {
+    let messages = store.fetch_headers_and_text(uids, &FETCH_FIELDS, FETCH_TEXT_LIMIT)?;
+    Ok(messages
+        .into_iter()
+        .map(|(uid, header, body)| (uid, tokenize(&header, &body)))
+        .collect())
+}
+
+// train a new model on the folders of each class
+pub fn train(store: &mut dyn MailStore, config: &ClassifierConfig) -> anyhow::Result<NaiveBayes> {
+    let mut model = NaiveBayes::default();
+    for (class, folders) in &config.classes {
+        for folder in folders {
+            store.open_folder(folder)?;
+            let uids = store.search_uids("ALL")?;
+            let documents = fetch_tokens(store, &uids)?;
+            log::info!(
+                "training {} on {} messages of {}",
+                class,
+                documents.len(),
+                folder
+            );
+            for (_, tokens) in documents {
+                model.train(class, &tokens);
+            }
+        }
+    }
+    model.check()?;
+    Ok(model)
+}
+
+// uids of the selected folder that are classified in a given class, with at least this probability
+// messages the model is not sure about are left alone
+pub(crate) fn uids_in_class(
+    store: &mut dyn MailStore,
+    model: &NaiveBayes,
+    uids: &[u32],
+    class: &str,
+    min_probability: f64,
+) -> anyhow::Result<Vec<u32>> {
+    let mut uids: Vec<u32> = fetch_tokens(store, uids)?
+        .into_iter()
+        .filter(|(_, tokens)| {
+            model
+                .classify(tokens)
+                .is_some_and(|(predicted, probability)| {
+                    predicted == class && probability >= min_probability
+                })
+        })
+        .map(|(uid, _)| uid)
+        .collect();
+    uids.sort();
+    Ok(uids)
+}
+
+// print the class of every message of a folder
+pub fn classify_folder(
+    store: &mut dyn MailStore,
+    model: &NaiveBayes,
+    folder: &str,
+) -> anyhow::Result<()> {
+    store.open_folder(folder)?;
+    let uids = store.search_uids("ALL")?;
+    let messages = store.fetch_headers_and_text(&uids, &FETCH_FIELDS, FETCH_TEXT_LIMIT)?;
+    for (uid, header, body) in messages {
+        let subject = header_value(&header, "Subject")
+            .map(|subject| decode_header(subject.as_bytes()))
+            .unwrap_or_default();
+        let (class, probability) = model
+            .classify(&tokenize(&header, &body))
+            .unwrap_or(("-".to_string(), 0.0));
+        println!(
+            "{uid:>8} {class:<20} {probability:>5.2} {subject}",
+            uid = uid,
+            class = class,
+            probability = probability,
+            subject = subject.chars().take(60).collect::<String>()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<String> {
+        words(text).collect()
+    }
+
+    fn model() -> NaiveBayes {
+        let mut model = NaiveBayes::default();
+        for text in [
+            "your receipt order total paid",
+            "receipt for your payment total",
+            "invoice paid order number",
+        ] {
+            model.train("receipts", &tokens(text));
+        }
+        for text in [
+            "lunch tomorrow with the team",
+            "meeting notes and agenda",
+            "photos from the weekend trip",
+        ] {
+            model.train("other", &tokens(text));
+        }
+        model
+    }
+
+    #[test]
+    fn classify_gives_the_likely_class_and_its_probability() {
+        let model = model();
+        let (class, probability) = model.classify(&tokens("receipt total paid")).unwrap();
+        assert_eq!(class, "receipts");
+        assert!(probability > 0.9 && probability <= 1.0, "{}", probability);
+
+        let (class, _) = model.classify(&tokens("team meeting agenda")).unwrap();
+        assert_eq!(class, "other");
+
+        // unknown words leave the class priors, equal here, ties go to the first class
+        let (class, probability) = model.classify(&tokens("zebra")).unwrap();
+        assert_eq!(class, "other");
+        assert!((probability - 0.5).abs() < 1e-9, "{}", probability);
+    }
+
+    #[test]
+    fn models_need_two_trained_classes() {
+        assert!(NaiveBayes::default().classify(&tokens("receipt")).is_none());
+        assert!(NaiveBayes::default().check().is_err());
+        let mut model = NaiveBayes::default();
+        model.train("receipts", &tokens("receipt total"));
+        assert!(model.check().is_err());
+        assert!(self::model().check().is_ok());
+    }
+
+    #[test]
+    fn quoted_printable_is_decoded() {
+        let cases: [(&[u8], &[u8]); 5] = [
+            (b"caf=C3=A9", "café".as_bytes()),
+            (b"long =\r\nline", b"long line"),
+            (b"long =\nline", b"long line"),
+            (b"a=3Db", b"a=b"),
+            // not an escape, kept as is
+            (b"100% =zz", b"100% =zz"),
+        ];
+        for (encoded, decoded) in cases {
+            assert_eq!(decode_quoted_printable(encoded), decoded);
+        }
+    }
+
+    #[test]
+    fn text_is_extracted_from_encoded_and_multipart_bodies() {
+        assert_eq!(extract_text(b"", b"plain text"), "plain text");
+        assert_eq!(
+            extract_text(
+                b"Content-Type: text/plain\r\nContent-Transfer-Encoding: base64\r\n",
+                b"aGVsbG8g\r\nd29ybGQ=\r\n"
+            ),
+            "hello world"
+        );
+        assert_eq!(
+            extract_text(b"Content-Type: text/html\r\n", b"<p>hi <b>there</b></p>").trim(),
+            "hi  there"
+        );
+        assert_eq!(extract_text(b"Content-Type: image/png\r\n", b"\x89PNG"), "");
+
+        let header = b"Content-Type: multipart/alternative; boundary=\"b1\"\r\n";
+        let body = b"preamble\r\n--b1\r\nContent-Type: text/plain\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\ncaf=C3=A9\r\n--b1\r\nContent-Type: image/png\r\n\r\nxxx\r\n--b1--\r\n";
+        let text = extract_text(header, body);
+        assert!(text.contains("café"), "{:?}", text);
+        assert!(!text.contains("xxx"), "{:?}", text);
+        assert!(!text.contains("preamble"), "{:?}", text);
+    }
+}
@@ -1,9 +1,11 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 // Structure holding configuration of the application
 // it is linked to configuration file and is updated by the application when using -s/--save option
 // managed by confy
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Configuration {
     pub imap_server: String,       // database hostname
     pub imap_port: u16,            // database port
@@ -12,6 +14,12 @@ pub struct Configuration {
 	pub rules_conf_path: String,   // where to find rule file
 	pub secure_store_path: String, // path to securre store
 	pub key_path: String,          // path to secure store key
+	pub journal_path: String,      // where to record actions done, empty to disable
+	pub schedule_state_path: String, // when rules with a schedule last ran
+	pub sieve_port: u16,           // ManageSieve port, on the IMAP server
+	pub sieve_tls: bool,           // use STARTTLS with ManageSieve server
+	pub sieve_script: String,      // name of the Sieve script uploaded
+	pub accounts: BTreeMap<String, AccountConfig>, // other accounts rules can move messages to
 }
 
 impl ::std::default::Default for Configuration {
@@ -23,7 +31,34 @@ impl ::std::default::Default for Configuration {
             imap_password: String::from(""),
 			rules_conf_path: String::from("rules.yaml"),
 			secure_store_path: String::from("config.json"),
-			key_path: String::from("secrets.key")
+			key_path: String::from("secrets.key"),
+			journal_path: String::from("journal.jsonl"),
+			schedule_state_path: String::from("schedule_state.json"),
+			sieve_port: 4190,
+			sieve_tls: true,
+			sieve_script: String::from("imap_sorter"),
+			accounts: BTreeMap::new(),
+        }
+    }
+}
+
+// another IMAP account, targets like account:name/folder move messages there
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AccountConfig {
+    pub imap_server: String,
+    pub imap_port: u16,
+    pub imap_username: String,
+    pub imap_password: String,
+}
+
+impl ::std::default::Default for AccountConfig {
+    fn default() -> AccountConfig {
+        AccountConfig {
+            imap_server: String::from("localhost"),
+            imap_port: 993,
+            imap_username: String::from("user"),
+            imap_password: String::from(""),
         }
     }
 }
@@ -0,0 +1,152 @@
+use crate::imap_tools::{header_value, record_journal_entry, RunOptions};
+use crate::journal::JournalEntry;
+use crate::rules::DedupeRule;
+use crate::store::MailStore;
+use chrono::{DateTime, FixedOffset};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+// header fields used to recognize a message without Message-ID
+const FALLBACK_FIELDS: [&str; 4] = ["Date", "From", "To", "Subject"];
+
+#[derive(Debug, Clone)]
+pub(crate) struct MessageCopy {
+    pub folder: String,
+    pub uid: u32,
+    pub date: Option<DateTime<FixedOffset>>,
+    pub message_id: Option<String>,
+}
+
+// identify a message, by its Message-ID or by a hash of its main header fields
+fn message_key(header: &[u8]) -> Option<(String, Option<String>)> {
+    if let Some(message_id) = header_value(header, "Message-ID") {
+        if !message_id.is_empty() {
+            return Some((message_id.to_lowercase(), Some(message_id)));
+        }
+    }
+    let fields: Vec<String> = FALLBACK_FIELDS
+        .iter()
+        .filter_map(|field| header_value(header, field))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    let mut hasher = DefaultHasher::new();
+    fields.hash(&mut hasher);
+    Some((format!("hash:{:016x}", hasher.finish()), None))
+}
+
+// fetch all messages of the folders and return the duplicated copies, all but the oldest one
+pub(crate) fn find_duplicates(
+    store: &mut dyn MailStore,
+    folders: &[String],
+) -> anyhow::Result<Vec<MessageCopy>> {
+    let mut fields = vec!["Message-ID"];
+    fields.extend(FALLBACK_FIELDS);
+    let mut copies: HashMap<String, Vec<(usize, MessageCopy)>> = HashMap::new();
+    for (index, folder) in folders.iter().enumerate() {
+        store.open_folder(folder)?;
+        let uids = store.search_uids("ALL")?;
+        if uids.is_empty() {
+            continue;
+        }
+        let headers = store.fetch_headers(&uids, &fields)?;
+        let dates: HashMap<u32, DateTime<FixedOffset>> =
+            store.fetch_dates(&uids)?.into_iter().collect();
+        log::debug!("{} messages in {}", headers.len(), folder);
+        for (uid, header) in headers {
+            let Some((key, message_id)) = message_key(&header) else {
+                log::debug!("cannot identify message {} in {}, skipping", uid, folder);
+                continue;
+            };
+            copies.entry(key).or_default().push((
+                index,
+                MessageCopy {
+                    folder: folder.clone(),
+                    uid,
+                    date: dates.get(&uid).copied(),
+                    message_id,
+                },
+            ));
+        }
+    }
+
+    let mut duplicates = Vec::new();
+    for (_, mut group) in copies {
+        if group.len() < 2 {
+            continue;
+        }
+        // keep the oldest one, then the one in the first folder listed, then the lowest uid
+        group.sort_by_key(|(index, copy)| (copy.date.is_none(), copy.date, *index, copy.uid));
+        duplicates.extend(group.into_iter().skip(1).map(|(_, copy)| copy));
+    }
+    Ok(duplicates)
+}
+
+pub fn dedupe_folders(
+    store: &mut dyn MailStore,
+    folder: &str,
+    dedupe: &DedupeRule,
+    options: &RunOptions,
+) -> anyhow::Result<String> {
+    let mut folders = vec![folder.to_string()];
+    for other in dedupe.folders.iter().flatten() {
+        if !folders.contains(other) {
+            folders.push(other.clone());
+        }
+    }
+    folders.retain(|folder| folder != &dedupe.target);
+
+    log::info!("dedupe     :{}", folders.join(", "));
+    let duplicates = find_duplicates(store, &folders)?;
+    if duplicates.is_empty() {
+        log::debug!("no duplicate found");
+        return Ok("no duplicate found".to_string());
+    }
+
+    for copy in &duplicates {
+        log::debug!(
+            "duplicate  : {} {} {}",
+            copy.folder,
+            copy.uid,
+            copy.message_id.as_deref().unwrap_or("-")
+        );
+    }
+
+    let result = if options.acts_on(dedupe.enable) {
+        // move duplicates folder by folder
+        let mut by_folder: BTreeMap<&str, Vec<&MessageCopy>> = BTreeMap::new();
+        for copy in &duplicates {
+            by_folder.entry(&copy.folder).or_default().push(copy);
+        }
+        for (folder, copies) in by_folder {
+            let uids: Vec<u32> = copies.iter().map(|copy| copy.uid).collect();
+            let mut entry = JournalEntry::new("dedupe", "dedupe", folder, Some(&dedupe.target));
+            entry.uids = uids.clone();
+            entry.message_ids = copies
+                .iter()
+                .filter_map(|copy| copy.message_id.clone())
+                .collect();
+            store.open_folder(folder)?;
+            store.move_messages(&uids, &dedupe.target)?;
+            record_journal_entry(options.journal, &entry);
+        }
+        format!("moved {} duplicated messages", duplicates.len())
+    } else {
+        format!(
+            "dedupe disabled, did not move {} duplicated messages",
+            duplicates.len()
+        )
+    };
+
+    log::info!(
+        folder = folder,
+        action = if options.acts_on(dedupe.enable) { "dedupe" } else { "skip" },
+        target = dedupe.target.as_str(),
+        matched = duplicates.len();
+        "{}",
+        result
+    );
+    Ok(result)
+}
@@ -1,8 +1,21 @@
+use crate::account::{move_to_account, Accounts};
+use crate::classifier::{uids_in_class, NaiveBayes};
+use crate::interactive::{Decision, Interactive};
+use crate::journal::{self, Journal, JournalEntry};
+use crate::lists::fetch_list_ids;
 use crate::rules;
-use crate::rules::Rule;
+use crate::rules::{RetentionAction, RetentionRule, Rule};
+use crate::schedule::ScheduleState;
+use crate::sorter::{FolderReport, RuleReport};
+use crate::store::MailStore;
+use crate::tags::TagExpression;
+use crate::thread::expand_to_threads;
+use base64::Engine;
 use imap::ImapConnection;
-use imap_proto::types::Address;
-use std::borrow::Cow;
+use imap_proto::types::NameAttribute;
+use imap_proto::types::ResponseCode;
+use std::collections::BTreeMap;
+use std::time::Instant;
 
 // #[derive(Default, Debug)]
 // struct Enveloppe {
@@ -19,91 +32,287 @@ use std::borrow::Cow;
 //     message_id: String,
 // }
 
-trait AddressExt {
-    fn to_formated(&self) -> String;
+// list all selectable folders on the server, along with the hierarchy delimiter
+pub fn list_mailboxes(
+    imap_session: &mut imap::Session<Box<dyn ImapConnection>>,
+) -> imap::error::Result<(Vec<String>, String)> {
+    let names = imap_session.list(None, Some("*"))?;
+    let delimiter = names
+        .iter()
+        .find_map(|name| name.delimiter().map(|d| d.to_string()))
+        .unwrap_or_else(|| "/".to_string());
+    let mailboxes = names
+        .iter()
+        .filter(|name| !name.attributes().contains(&NameAttribute::NoSelect))
+        .map(|name| name.name().to_string())
+        .collect();
+    Ok((mailboxes, delimiter))
 }
 
-impl AddressExt for imap_proto::types::Address<'_> {
-    fn to_formated(&self) -> String {
-        // extract mailbox and host and concatenate with a @
-        format!(
-            // target format
-            "{}{}{}@{}",
-            // get name
-            match self.name.as_ref() {
-                Some(buffer) => format!("<{}> ", String::from_utf8_lossy(buffer.as_ref())),
-                None => "".into(),
-            },
-            // get mailbox
-            match self.mailbox.as_ref() {
-                Some(buffer) => String::from_utf8_lossy(buffer.as_ref()),
-                None => "?".into(),
-            },
-            // get additionnal routing information
-            match self.adl.as_ref() {
-                Some(buffer) => String::from_utf8_lossy(buffer.as_ref()),
-                None => "".into(),
-            },
-            // get host
-            match self.host.as_ref() {
-                Some(buffer) => String::from_utf8_lossy(buffer.as_ref()),
-                None => "?".into(),
-            },
-        )
+// address of a From header, like john@doe.com of John Doe <john@doe.com>
+pub fn address(from: &str) -> String {
+    match from.rsplit_once('<') {
+        Some((_, address)) => address.trim_end_matches('>').trim().to_lowercase(),
+        None => from.trim().to_lowercase(),
+    }
+}
+
+// value of a header field, unfolded, from a raw header block
+pub(crate) fn header_value(header: &[u8], name: &str) -> Option<String> {
+    let header = String::from_utf8_lossy(header);
+    let mut value: Option<String> = None;
+    for line in header.lines() {
+        if line.starts_with([' ', '\t']) {
+            // continuation of the previous field
+            if let Some(value) = value.as_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if value.is_some() {
+            break;
+        }
+        if let Some((field, field_value)) = line.split_once(':') {
+            if field.trim().eq_ignore_ascii_case(name) {
+                value = Some(field_value.trim().to_string());
+            }
+        }
     }
+    value
 }
 
-fn get_addresses(addresses_vec_opt: Option<&Vec<Address>>) -> String {
-    match addresses_vec_opt {
-        // scan all Vec<Addresses<>> and make a string
-        // of all addreeses in one string coma separated
-        Some(addresses_vec) => {
-            addresses_vec
-                // goes though all addresses
-                .iter()
-                // properly format addreses
-                .map(|addr| addr.to_formated())
-                // collect resutl in a Vec<String>
-                .collect::<Vec<String>>()
-                // join them in a string, separated by ,
-                .join(", ")
+// decode bytes of a given charset, charsets other than utf-8 and latin-1 are read as utf-8
+fn decode_charset(bytes: &[u8], charset: &str) -> String {
+    match charset.to_ascii_lowercase().as_str() {
+        "iso-8859-1" | "iso-8859-15" | "latin1" | "windows-1252" => {
+            bytes.iter().map(|&b| b as char).collect()
         }
-        _ => "UNKNOWN".to_string(),
+        _ => String::from_utf8_lossy(bytes).to_string(),
     }
 }
 
-pub fn search_and_move(
-    imap_session: &mut imap::Session<Box<dyn ImapConnection>>,
+// decode one encoded word =?charset?encoding?text?= (rfc2047)
+fn decode_encoded_word(word: &str) -> Option<String> {
+    let inner = word.strip_prefix("=?")?.strip_suffix("?=")?;
+    let mut parts = inner.splitn(3, '?');
+    let charset = parts.next()?;
+    let encoding = parts.next()?;
+    let text = parts.next()?;
+    let bytes = match encoding {
+        "B" | "b" => base64::engine::general_purpose::STANDARD
+            .decode(text)
+            .ok()?,
+        "Q" | "q" => {
+            let mut bytes = Vec::new();
+            let mut chars = text.bytes();
+            while let Some(c) = chars.next() {
+                match c {
+                    b'_' => bytes.push(b' '),
+                    b'=' => {
+                        let hex = [chars.next()?, chars.next()?];
+                        bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+                    }
+                    c => bytes.push(c),
+                }
+            }
+            bytes
+        }
+        _ => return None,
+    };
+    Some(decode_charset(&bytes, charset))
+}
+
+// decode a header value that may contain rfc2047 encoded words, like most subjects
+pub(crate) fn decode_header(value: &[u8]) -> String {
+    let value = String::from_utf8_lossy(value);
+    let mut decoded = String::new();
+    let mut rest: &str = &value;
+    let mut previous_encoded = false;
+    while let Some(start) = rest.find("=?") {
+        // encoded words are =?charset?encoding?text?=, find the end after the 3 question marks
+        let end = rest[start + 2..]
+            .match_indices('?')
+            .nth(2)
+            .map(|(index, _)| start + 2 + index)
+            .filter(|&index| rest[index..].starts_with("?="));
+        let Some(end) = end else {
+            break;
+        };
+        let between = &rest[..start];
+        match decode_encoded_word(&rest[start..end + 2]) {
+            Some(word) => {
+                // white space between two encoded words is not displayed
+                if !(previous_encoded && between.trim().is_empty()) {
+                    decoded.push_str(between);
+                }
+                decoded.push_str(&word);
+                previous_encoded = true;
+            }
+            None => {
+                decoded.push_str(&rest[..end + 2]);
+                previous_encoded = false;
+            }
+        }
+        rest = &rest[end + 2..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+// fetch Message-ID of messages, as a list of (uid, message id)
+pub(crate) fn fetch_message_ids(
+    store: &mut dyn MailStore,
+    uids: &[u32],
+) -> anyhow::Result<Vec<(u32, String)>> {
+    let messages = store.fetch_headers(uids, &["Message-ID"])?;
+    Ok(messages
+        .into_iter()
+        .filter_map(|(uid, header)| Some((uid, header_value(&header, "Message-ID")?)))
+        .collect())
+}
+
+// how rules are applied, the same for all rules of a run
+pub struct RunOptions<'a> {
+    // do not move message (aka simlation mode)
+    pub nomove: bool,
+    // execute all rules, even disabled one
+    pub force: bool,
+    // let retention rules delete messages
+    pub allow_delete: bool,
+    pub journal: &'a Journal,
+    pub model: Option<&'a NaiveBayes>,
+    // other accounts, for account:name/folder targets
+    pub accounts: Option<&'a Accounts<'a>>,
+    // last runs of rules with a schedule, None runs them all
+    pub schedule: Option<&'a ScheduleState>,
+    // confirm each move on the terminal
+    pub interactive: Option<&'a Interactive>,
+}
+
+// moves for real, on enabled rules only, without journal nor the extras of the command line
+impl Default for RunOptions<'_> {
+    fn default() -> Self {
+        RunOptions {
+            nomove: false,
+            force: false,
+            allow_delete: false,
+            journal: &journal::DISABLED,
+            model: None,
+            accounts: None,
+            schedule: None,
+            interactive: None,
+        }
+    }
+}
+
+impl RunOptions<'_> {
+    // tell if an enabled (or not) rule should really act
+    pub fn acts_on(&self, enable: bool) -> bool {
+        (enable && !self.nomove) || self.force
+    }
+}
+
+// fill in uids and message ids of a journal entry, before they are moved away
+pub(crate) fn fill_journal_entry(
+    store: &mut dyn MailStore,
+    journal: &Journal,
+    entry: &mut JournalEntry,
+    uids: &[u32],
+) -> anyhow::Result<()> {
+    entry.uids = uids.to_vec();
+    if journal.is_enabled() {
+        entry.message_ids = fetch_message_ids(store, uids)?
+            .into_iter()
+            .map(|(_, message_id)| message_id)
+            .collect();
+    }
+    Ok(())
+}
+
+// failing to write the journal should not stop sorting
+pub(crate) fn record_journal_entry(journal: &Journal, entry: &JournalEntry) {
+    if let Err(error) = journal.record(entry) {
+        log::error!("{:#}", error);
+    }
+}
+
+// apply one rule on a folder, what went wrong ends up in the report
+pub(crate) fn search_and_move(
+    store: &mut dyn MailStore,
     rule: rules::Rule,
     folder: String,
-    nomove: bool,
-    force: bool,
-) -> imap::error::Result<Option<String>> {
+    options: &RunOptions,
+) -> RuleReport {
+    let started = Instant::now();
+    let mut report = RuleReport {
+        rule: rule.name.clone(),
+        target: rule.target.clone(),
+        ..Default::default()
+    };
+    if let Err(error) = move_matching(store, &rule, &folder, options, &mut report) {
+        log::error!(
+            folder = folder.as_str(),
+            rule = rule.name.as_str(),
+            action = "error";
+            "rule {} failed: {:#}",
+            rule.name,
+            error
+        );
+        report.errors.push(format!("{:#}", error));
+    }
+    report.moved = report.uids.len();
+    report.duration_ms = started.elapsed().as_millis() as u64;
+    report
+}
+
+fn move_matching(
+    store: &mut dyn MailStore,
+    rule: &Rule,
+    folder: &str,
+    options: &RunOptions,
+    report: &mut RuleReport,
+) -> anyhow::Result<()> {
     // we want to fetch the first email in the INBOX mailbox
-    imap_session.select(folder)?;
+    store.open_folder(folder)?;
 
-    // fetch message number 1 in this mailbox, along with its RFC822 field.
-    // RFC 822 dictates the format of the body of e-mails
-    let search_set = imap_session.search(rule.filter.clone())?;
-    if search_set.len() == 0 {
+    // search with UIDs, so that they stay valid while we work on them
+    let mut search_vec = store.search_uids(&rule.search_filter())?;
+    report.matched = search_vec.len();
+    if search_vec.is_empty() {
         log::debug!("nothing to move :{}", rule.name_and_tag());
-        return Ok(Some("nothing to move".to_string()));
+        return Ok(());
     }
 
-    log::info!("processing :{}", rule.name_and_tag());
+    log::info!(folder = folder, rule = rule.name.as_str(); "processing :{}", rule.name_and_tag());
     log::debug!("{}", rule.as_string());
 
-    // collect found message to create a reference string for fetch
-    let search_vec: Vec<u32> = search_set.clone().into_iter().collect();
-    let search: String = search_vec
-        .iter()
-        .map(|n| n.to_string())
-        .collect::<Vec<String>>()
-        .join(",");
+    // keep only what the classifier puts in the rule class
+    if let Some(class) = &rule.classifier {
+        let Some(model) = options.model else {
+            log::error!(
+                "no classifier model loaded, skipping :{}",
+                rule.name_and_tag()
+            );
+            report.skipped = Some("no classifier model".to_string());
+            return Ok(());
+        };
+        search_vec = uids_in_class(store, model, &search_vec, class, rule.min_probability())?;
+        report.matched = search_vec.len();
+        if search_vec.is_empty() {
+            log::debug!("nothing classified as {} :{}", class, rule.name_and_tag());
+            return Ok(());
+        }
+    }
+    // bring along the rest of the conversation
+    if rule.thread {
+        search_vec = expand_to_threads(store, &search_vec)?;
+        report.matched = search_vec.len();
+    }
 
     if log::log_enabled!(log::Level::Debug) {
-        let messages = imap_session.fetch(search.clone(), "ALL")?;
         // we are in debug mode, let's get all details of messages we are going to move properly formated
+        let messages = store.fetch_headers(&search_vec, &["Date", "Subject", "From", "To"])?;
 
         // print header of found mails
         log::trace!(
@@ -114,72 +323,362 @@ pub fn search_and_move(
             to = "to"
         );
 
-        // create a decent value as default for missing header parts
-        let mydefault: &Cow<'_, [u8]> = &Cow::Borrowed("-".as_bytes());
-        // iterate on all message an print them
-        for message in messages.iter() {
-            let envelope = message
-                .envelope()
-                .expect("message missing envelope")
-                .to_owned();
-
-            let date =
-                String::from_utf8_lossy(envelope.date.as_ref().unwrap_or(mydefault).as_ref());
-
-            // subject more likely to not me utf8
-            let subject =
-                String::from_utf8_lossy(envelope.subject.as_ref().unwrap_or(mydefault).as_ref());
-            let from_addresses = get_addresses(envelope.from.as_ref());
-            let to_addresses = get_addresses(envelope.to.as_ref());
-
+        // iterate on all message an print them, with a decent value for missing header parts
+        for (_, header) in messages {
+            let field = |name: &str| {
+                header_value(&header, name)
+                    .map(|value| decode_header(value.as_bytes()))
+                    .unwrap_or("-".to_string())
+            };
             log::trace!(
                 "{date:<22} {subject:<40} {from:<30} {to:<30}",
-                date = date.chars().take(22).collect::<String>(),
-                subject = subject.chars().take(40).collect::<String>(),
-                from = from_addresses.chars().take(30).collect::<String>(),
-                to = to_addresses.chars().take(30).collect::<String>()
+                date = field("Date").chars().take(22).collect::<String>(),
+                subject = field("Subject").chars().take(40).collect::<String>(),
+                from = field("From").chars().take(30).collect::<String>(),
+                to = field("To").chars().take(30).collect::<String>()
             );
         }
     };
+    // let the user look at the messages, and choose which ones move
+    if let (true, Some(interactive)) = (options.acts_on(rule.enable), options.interactive) {
+        let declined = match interactive.confirm(store, rule, folder, &search_vec)? {
+            Decision::Move(uids) if !uids.is_empty() => {
+                search_vec = uids;
+                None
+            }
+            Decision::Move(_) | Decision::No => Some("declined"),
+            Decision::SkipRule => Some("rule skipped"),
+            Decision::Quit => Some("quit"),
+        };
+        if let Some(reason) = declined {
+            report.skipped = Some(reason.to_string());
+            log::info!(
+                folder = folder,
+                rule = rule.name.as_str(),
+                action = "skip",
+                target = rule.target.as_str(),
+                matched = report.matched;
+                "{}, did not process {} messages",
+                reason,
+                search_vec.len()
+            );
+            return Ok(());
+        }
+    }
+
     // do the actual move or not according to flags and set return a message
-    let result = if (rule.enable && !nomove) || force {
-        // let's move them
-        imap_session.mv(search, rule.target)?;
+    let result = if options.acts_on(rule.enable) {
+        if let Some(flags) = &rule.flags {
+            store.add_flags(&search_vec, flags)?;
+        }
+        if let Some((account, target)) = rule.target_account() {
+            // copied to the other account, then deleted here once found there
+            let Some(accounts) = options.accounts else {
+                anyhow::bail!("no other account to move messages to {}", rule.target);
+            };
+            let mut entry = JournalEntry::new("move", &rule.name, folder, Some(&rule.target));
+            let mut moved = Vec::new();
+            let result = move_to_account(store, accounts, account, target, &search_vec, &mut moved);
+            // what was moved before a failure is journaled too
+            if !moved.is_empty() {
+                (entry.uids, entry.message_ids) = moved.into_iter().unzip();
+                report.uids.extend(&entry.uids);
+                record_journal_entry(options.journal, &entry);
+            }
+            if let Err(error) = result {
+                log::error!(
+                    account = account,
+                    folder = folder,
+                    rule = rule.name.as_str(),
+                    action = "error";
+                    "cannot move to account {}: {:#}",
+                    account,
+                    error
+                );
+                report
+                    .errors
+                    .push(format!("cannot move to account {}: {:#}", account, error));
+            }
+        } else if rule.target_uses_list_id() {
+            // each mailing list goes to its own folder
+            let mut by_target: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+            for (uid, list_id) in fetch_list_ids(store, &search_vec)? {
+                match list_id {
+                    Some(list_id) => by_target
+                        .entry(rule.target_for_list(&list_id))
+                        .or_default()
+                        .push(uid),
+                    None => log::debug!("message {} has no List-Id, not moved", uid),
+                }
+            }
+            for (target, uids) in &by_target {
+                let mut entry = JournalEntry::new("move", &rule.name, folder, Some(target));
+                fill_journal_entry(store, options.journal, &mut entry, uids)?;
+                store.move_messages(uids, target)?;
+                report.uids.extend(uids);
+                record_journal_entry(options.journal, &entry);
+            }
+        } else {
+            let mut entry = JournalEntry::new("move", &rule.name, folder, Some(&rule.target));
+            fill_journal_entry(store, options.journal, &mut entry, &search_vec)?;
+            // let's move them
+            store.move_messages(&search_vec, &rule.target)?;
+            report.uids.extend(&search_vec);
+            record_journal_entry(options.journal, &entry);
+        }
         // and tell them how much we worked
-        format!("processed {} messages", search_set.len())
+        format!("processed {} messages", report.uids.len())
     } else {
         // skip move as rule is disabled or running in simulation
+        let reason = if rule.enable {
+            "simulation"
+        } else {
+            "rule disabled"
+        };
+        report.skipped = Some(reason.to_string());
         format!(
             "rule disabled, did not process {} messages",
-            search_set.len()
+            search_vec.len()
         )
     };
 
-    log::info!("{}", result);
+    log::info!(
+        account = rule.target_account().map(|(account, _)| account),
+        folder = folder,
+        rule = rule.name.as_str(),
+        action = if report.skipped.is_some() { "skip" } else { "move" },
+        target = rule.target.as_str(),
+        matched = report.matched,
+        moved = report.uids.len(),
+        uids:% = uid_set(&report.uids);
+        "{}",
+        result
+    );
 
-    Ok(Some(result))
+    Ok(())
 }
 
-pub fn apply_rules_on_folder(
-    mut imap_session: &mut imap::Session<Box<dyn ImapConnection>>,
-    rules : Vec<Rule>,
+pub(crate) fn apply_rules_on_folder(
+    store: &mut dyn MailStore,
+    rules: Vec<Rule>,
     folder_name: &String,
-    tag: &Option<String>,
-    nomove: bool,
-    force: bool,
-) {
+    tag: &Option<TagExpression>,
+    options: &RunOptions,
+) -> FolderReport {
     log::info!(
+        folder = folder_name.as_str();
         "-------------------- Processing for {} ----------",
         folder_name
     );
 
+    let started = Instant::now();
+    let mut report = FolderReport {
+        folder: folder_name.clone(),
+        ..Default::default()
+    };
     for rule in rules {
         if !rule.match_tag(tag) {
             log::debug!("skipping   :\n{}", rule.as_string());
             continue;
         };
+        if let Some(schedule) = options.schedule {
+            if !schedule.is_due(folder_name, &rule) {
+                log::debug!("not due yet :{}", rule.name_and_tag());
+                continue;
+            }
+        }
+        if let Some(interactive) = options.interactive {
+            if interactive.has_quit() {
+                break;
+            }
+            if interactive.skips(&rule) {
+                log::debug!("skipped for this run :{}", rule.name_and_tag());
+                continue;
+            }
+        }
 
-        search_and_move(&mut imap_session, rule, folder_name.clone(), nomove, force).unwrap();
+        // a failing rule does not stop the others
+        report
+            .rules
+            .push(search_and_move(store, rule, folder_name.clone(), options));
     }
+    report.duration_ms = started.elapsed().as_millis() as u64;
     log::info!("done");
+    report
+}
+
+// move messages to a folder, creating the folder when the server tells us it does not exist
+pub(crate) fn uid_move_or_create(
+    imap_session: &mut imap::Session<Box<dyn ImapConnection>>,
+    uids: &str,
+    target: &str,
+) -> imap::error::Result<()> {
+    match imap_session.uid_mv(uids, target) {
+        Err(imap::Error::No(no)) if no.code == Some(ResponseCode::TryCreate) => {
+            log::info!("creating folder {}", target);
+            imap_session.create(target)?;
+            imap_session.uid_mv(uids, target)
+        }
+        result => result,
+    }
+}
+
+// uids of sorted lists as ranges, so that whole folders make short commands
+pub(crate) fn uid_set(uids: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &uid in uids {
+        match ranges.last_mut() {
+            Some((_, end)) if end.checked_add(1) == Some(uid) => *end = uid,
+            _ => ranges.push((uid, uid)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}:{}", start, end)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+// delete messages of the selected folder
+pub(crate) fn delete_uids(
+    imap_session: &mut imap::Session<Box<dyn ImapConnection>>,
+    uids: &[u32],
+) -> anyhow::Result<()> {
+    // a plain EXPUNGE would also remove messages flagged \Deleted by someone else
+    if !imap_session.capabilities()?.has_str("UIDPLUS") {
+        anyhow::bail!(
+            "server has no UIDPLUS, {} messages not deleted to leave other deleted messages alone",
+            uids.len()
+        );
+    }
+    let uid_set = uid_set(uids);
+    imap_session.uid_store(&uid_set, "+FLAGS.SILENT (\\Deleted)")?;
+    imap_session.uid_expunge(&uid_set)?;
+    Ok(())
+}
+
+pub(crate) fn apply_retention(
+    store: &mut dyn MailStore,
+    retention: &RetentionRule,
+    folder: &str,
+    options: &RunOptions,
+) -> anyhow::Result<String> {
+    store.open_folder(folder)?;
+
+    // the limit date is computed at each run
+    let today = chrono::Local::now().date_naive();
+    let filter = retention.search_filter(today);
+    let uids = store.search_uids(&filter)?;
+    if uids.is_empty() {
+        log::debug!("nothing to clean :{}", retention.name);
+        return Ok("nothing to clean".to_string());
+    }
+
+    log::info!("retention  :{}", retention.name);
+    log::debug!("{}\n\tsearch: {}", retention.as_string(), filter);
+
+    if !options.acts_on(retention.enable) {
+        let result = format!(
+            "retention disabled, did not process {} messages",
+            uids.len()
+        );
+        log::info!(
+            folder = folder,
+            rule = retention.name.as_str(),
+            action = "skip",
+            matched = uids.len();
+            "{}",
+            result
+        );
+        return Ok(result);
+    }
+
+    let result = match retention.action {
+        RetentionAction::Delete => {
+            if !options.allow_delete {
+                log::warn!(
+                    "{} messages to delete in {}, skipped as --allow-delete is not set",
+                    uids.len(),
+                    folder
+                );
+                return Ok(format!(
+                    "deletion not allowed, kept {} messages",
+                    uids.len()
+                ));
+            }
+            let mut entry = JournalEntry::new("delete", &retention.name, folder, None);
+            fill_journal_entry(store, options.journal, &mut entry, &uids)?;
+            store.delete_messages(&uids)?;
+            record_journal_entry(options.journal, &entry);
+            format!("deleted {} messages", uids.len())
+        }
+        RetentionAction::Archive => {
+            // group messages by target folder, as it may depend on the message date
+            let mut by_target: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+            for (uid, date) in store.fetch_dates(&uids)? {
+                if let Some(target) = retention.target_for(&date.date_naive()) {
+                    by_target.entry(target).or_default().push(uid);
+                }
+            }
+            let mut archived = 0;
+            for (target, uids) in &by_target {
+                log::debug!("archiving {} messages to {}", uids.len(), target);
+                let mut entry = JournalEntry::new("archive", &retention.name, folder, Some(target));
+                fill_journal_entry(store, options.journal, &mut entry, uids)?;
+                store.move_messages(uids, target)?;
+                record_journal_entry(options.journal, &entry);
+                archived += uids.len();
+            }
+            // messages whose date could not be fetched stay where they are
+            if archived < uids.len() {
+                log::warn!(
+                    "{} messages of {} without a date, not archived",
+                    uids.len() - archived,
+                    folder
+                );
+            }
+            format!("archived {} messages", archived)
+        }
+    };
+
+    log::info!(
+        folder = folder,
+        rule = retention.name.as_str(),
+        action = match retention.action {
+            RetentionAction::Delete => "delete",
+            RetentionAction::Archive => "archive",
+        },
+        matched = uids.len(),
+        uids:% = uid_set(&uids);
+        "{}",
+        result
+    );
+    Ok(result)
+}
+
+pub fn apply_retention_on_folder(
+    store: &mut dyn MailStore,
+    retentions: Vec<RetentionRule>,
+    folder_name: &str,
+    options: &RunOptions,
+) {
+    for retention in retentions {
+        // a failed retention must not stop the others, nor the daemon
+        if let Err(error) = apply_retention(store, &retention, folder_name, options) {
+            log::error!(
+                folder = folder_name,
+                rule = retention.name.as_str(),
+                action = "error";
+                "retention {} failed: {:#}",
+                retention.name,
+                error
+            );
+        }
+    }
 }
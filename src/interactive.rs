@@ -0,0 +1,197 @@
+use crate::imap_tools::{decode_header, header_value};
+use crate::rules::Rule;
+use crate::store::MailStore;
+use anyhow::Context;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+
+// with --interactive, the messages a rule is about to move are listed and the move is confirmed,
+// for all of them, some of them by index, or none
+
+// what to do with the messages a rule matched
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    // move these uids, all those matched or a selection
+    Move(Vec<u32>),
+    // leave them, this time
+    No,
+    // leave them, and skip the rule for the rest of the run
+    SkipRule,
+    // leave them, and stop moving anything
+    Quit,
+}
+
+// answers given so far during a run
+#[derive(Debug, Default)]
+pub struct Interactive {
+    skipped_rules: RefCell<HashSet<String>>,
+    quit: Cell<bool>,
+}
+
+// indexes like `1,3-5 8`, from 1 to count
+pub fn parse_selection(text: &str, count: usize) -> anyhow::Result<Vec<usize>> {
+    let mut indexes = Vec::new();
+    for part in text.split(|c: char| c == ',' || c.is_whitespace()) {
+        if part.is_empty() {
+            continue;
+        }
+        let index = |text: &str| -> anyhow::Result<usize> {
+            text.trim()
+                .parse()
+                .with_context(|| format!("{} is not an index or a range of indexes", part))
+        };
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (index(first)?, index(last)?),
+            None => (index(part)?, index(part)?),
+        };
+        if first == 0 || last > count || first > last {
+            anyhow::bail!("{} is not between 1 and {}", part, count);
+        }
+        for index in first..=last {
+            if !indexes.contains(&index) {
+                indexes.push(index);
+            }
+        }
+    }
+    Ok(indexes)
+}
+
+// the answer to a question, None at the end of the input
+fn prompt(question: &str) -> anyhow::Result<Option<String>> {
+    print!("{} ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(answer.trim().to_lowercase()))
+}
+
+impl Interactive {
+    pub fn has_quit(&self) -> bool {
+        self.quit.get()
+    }
+
+    // tell if a rule was skipped for the rest of the run
+    pub fn skips(&self, rule: &Rule) -> bool {
+        self.skipped_rules.borrow().contains(&rule.name)
+    }
+
+    // list the messages matched by a rule, then ask what to do with them
+    pub fn confirm(
+        &self,
+        store: &mut dyn MailStore,
+        rule: &Rule,
+        folder: &str,
+        uids: &[u32],
+    ) -> anyhow::Result<Decision> {
+        let headers = store.fetch_headers(uids, &["Date", "Subject", "From"])?;
+        println!(
+            "\nrule {} matched {} messages in {}, to move to {}",
+            rule.name,
+            uids.len(),
+            folder,
+            rule.target
+        );
+        println!(
+            "{index:>4} {date:<22} {from:<30} subject",
+            index = "#",
+            date = "date",
+            from = "from"
+        );
+        for (index, uid) in uids.iter().enumerate() {
+            let header = headers
+                .iter()
+                .find(|(fetched, _)| fetched == uid)
+                .map(|(_, header)| header.as_slice())
+                .unwrap_or_default();
+            let field = |name: &str| {
+                header_value(header, name)
+                    .map(|value| decode_header(value.as_bytes()))
+                    .unwrap_or("-".to_string())
+            };
+            println!(
+                "{index:>4} {date:<22} {from:<30} {subject}",
+                index = index + 1,
+                date = field("Date").chars().take(22).collect::<String>(),
+                from = field("From").chars().take(30).collect::<String>(),
+                subject = field("Subject")
+            );
+        }
+        let decision = self.ask(uids)?;
+        match decision {
+            Decision::SkipRule => {
+                self.skipped_rules.borrow_mut().insert(rule.name.clone());
+            }
+            Decision::Quit => self.quit.set(true),
+            _ => {}
+        }
+        Ok(decision)
+    }
+
+    // ask until the answer makes sense, the end of the input answers quit
+    fn ask(&self, uids: &[u32]) -> anyhow::Result<Decision> {
+        loop {
+            let question = "move them? [y]es, [n]o, [s]elect, skip-[r]ule, [q]uit (y/n/s/r/q)";
+            let Some(answer) = prompt(question)? else {
+                return Ok(Decision::Quit);
+            };
+            match answer.as_str() {
+                "y" | "yes" => return Ok(Decision::Move(uids.to_vec())),
+                "n" | "no" => return Ok(Decision::No),
+                "s" | "select" => loop {
+                    let Some(answer) = prompt("indexes to move, like 1,3-5:")? else {
+                        return Ok(Decision::Quit);
+                    };
+                    match parse_selection(&answer, uids.len()) {
+                        Ok(indexes) => {
+                            let selected = indexes.iter().map(|index| uids[index - 1]).collect();
+                            return Ok(Decision::Move(selected));
+                        }
+                        Err(error) => println!("{}", error),
+                    }
+                },
+                "r" | "skip-rule" => return Ok(Decision::SkipRule),
+                "q" | "quit" => return Ok(Decision::Quit),
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selections() {
+        let cases: [(&str, Option<&[usize]>); 16] = [
+            ("", Some(&[])),
+            ("2", Some(&[2])),
+            ("1,3", Some(&[1, 3])),
+            ("1 3", Some(&[1, 3])),
+            (" 1,, 3 ", Some(&[1, 3])),
+            ("2-4", Some(&[2, 3, 4])),
+            ("1,3-5 8", Some(&[1, 3, 4, 5, 8])),
+            ("5-5", Some(&[5])),
+            // in the order given, each index once
+            ("4,1-2,2", Some(&[4, 1, 2])),
+            ("0", None),
+            ("9", None),
+            ("5-3", None),
+            ("7-9", None),
+            ("-2", None),
+            ("1 - 3", None),
+            ("two", None),
+        ];
+        for (text, indexes) in cases {
+            assert_eq!(
+                parse_selection(text, 8).ok().as_deref(),
+                indexes,
+                "selection {:?}",
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use crate::account::Session;
+use crate::imap_tools::{uid_move_or_create, uid_set};
+use crate::sieve::quoted;
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// one action done on the mailbox, written as one JSON line in the journal file
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JournalEntry {
+    // unique id, named by the undo entry that undid it
+    pub id: String,
+    pub timestamp: String,
+    pub action: String,
+    pub rule: String,
+    pub folder: String,
+    pub target: Option<String>,
+    pub uids: Vec<u32>,
+    pub message_ids: Vec<String>,
+    // for an undo entry, the id of the entry it undid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub undoes: Option<String>,
+}
+
+// entries written within the same nanosecond still get their own id
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl JournalEntry {
+    pub fn new(action: &str, rule: &str, folder: &str, target: Option<&str>) -> Self {
+        let now = chrono::Local::now();
+        JournalEntry {
+            id: format!(
+                "{}-{}-{}",
+                now.timestamp_nanos_opt().unwrap_or_default(),
+                std::process::id(),
+                NEXT_ID.fetch_add(1, Ordering::Relaxed)
+            ),
+            timestamp: now.to_rfc3339(),
+            action: action.to_string(),
+            rule: rule.to_string(),
+            folder: folder.to_string(),
+            target: target.map(|target| target.to_string()),
+            uids: Vec::new(),
+            message_ids: Vec::new(),
+            undoes: None,
+        }
+    }
+}
+
+// append only record of what has been done, an empty path disables it
+pub struct Journal {
+    path: String,
+}
+
+// the journal of options that name none, it records nothing
+pub(crate) static DISABLED: Journal = Journal {
+    path: String::new(),
+};
+
+impl Journal {
+    pub fn new(path: &str) -> Self {
+        Journal {
+            path: path.to_string(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.path.is_empty()
+    }
+
+    pub fn record(&self, entry: &JournalEntry) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open journal: {}", self.path))?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)
+            .with_context(|| format!("Failed to write journal: {}", self.path))?;
+        Ok(())
+    }
+
+    // entries recorded so far, oldest first
+    pub fn entries(&self) -> Result<Vec<JournalEntry>> {
+        if !self.is_enabled() {
+            anyhow::bail!("no journal configured");
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open journal: {}", self.path))?;
+        let mut entries = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(error) => log::warn!("journal line {} ignored: {}", number + 1, error),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// actions that moved messages to a folder of this account, and can move them back
+fn is_undoable(entry: &JournalEntry) -> bool {
+    matches!(entry.action.as_str(), "move" | "archive" | "dedupe")
+        && entry
+            .target
+            .as_ref()
+            .is_some_and(|target| !target.starts_with("account:"))
+}
+
+// the latest entries not undone yet, latest first
+pub fn entries_to_undo(entries: &[JournalEntry], count: usize) -> Vec<JournalEntry> {
+    let undone: HashSet<&String> = entries
+        .iter()
+        .filter(|entry| entry.action == "undo")
+        .filter_map(|entry| entry.undoes.as_ref())
+        .collect();
+    entries
+        .iter()
+        .rev()
+        .filter(|entry| is_undoable(entry) && !undone.contains(&entry.id))
+        .take(count)
+        .cloned()
+        .collect()
+}
+
+// move the messages of an entry back to the folder they came from, found by their Message-ID
+pub fn undo_entry(
+    imap_session: &mut Session,
+    journal: &Journal,
+    entry: &JournalEntry,
+    nomove: bool,
+) -> Result<usize> {
+    let Some(target) = &entry.target else {
+        anyhow::bail!("{} of rule {} has no target", entry.action, entry.rule);
+    };
+    if target.starts_with("account:") {
+        anyhow::bail!("messages moved to {} cannot be moved back", target);
+    }
+    imap_session.select(target)?;
+    let mut uids = Vec::new();
+    for message_id in &entry.message_ids {
+        uids.extend(imap_session.uid_search(format!("HEADER Message-ID {}", quoted(message_id)))?);
+    }
+    uids.sort();
+    uids.dedup();
+    if uids.len() < entry.message_ids.len() {
+        log::warn!(
+            "{} of {} messages are no longer in {}",
+            entry.message_ids.len() - uids.len(),
+            entry.message_ids.len(),
+            target
+        );
+    }
+    if nomove {
+        return Ok(uids.len());
+    }
+    if !uids.is_empty() {
+        uid_move_or_create(imap_session, &uid_set(&uids), &entry.folder)?;
+    }
+    // recorded even when nothing was left to move, so that the next undo goes past this entry
+    let mut undo = JournalEntry::new("undo", &entry.rule, target, Some(&entry.folder));
+    undo.undoes = Some(entry.id.clone());
+    undo.uids = uids.clone();
+    undo.message_ids = entry.message_ids.clone();
+    if let Err(error) = journal.record(&undo) {
+        log::error!("{:#}", error);
+    }
+    Ok(uids.len())
+}
+
+// entries and messages by action, rule and target, of the entries recorded since a date
+pub fn stats(
+    entries: &[JournalEntry],
+    since: Option<NaiveDate>,
+) -> BTreeMap<(String, String, String), (usize, usize)> {
+    let mut stats: BTreeMap<(String, String, String), (usize, usize)> = BTreeMap::new();
+    for entry in entries {
+        if let Some(since) = since {
+            let date = chrono::DateTime::parse_from_rfc3339(&entry.timestamp)
+                .map(|timestamp| timestamp.date_naive());
+            if date.map_or(true, |date| date < since) {
+                continue;
+            }
+        }
+        let key = (
+            entry.action.clone(),
+            entry.rule.clone(),
+            entry.target.clone().unwrap_or_default(),
+        );
+        let counts = stats.entry(key).or_default();
+        counts.0 += 1;
+        counts.1 += entry.uids.len();
+    }
+    stats
+}
@@ -0,0 +1,255 @@
+use crate::imap_tools::{address, header_value};
+use crate::rules::{Rule, RulesSet};
+use crate::store::MailStore;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// a sender needs at least this many filed messages to be learned
+const MIN_COUNT: usize = 3;
+// share of the messages of a sender that must be in the same folder
+const MIN_PRECISION: f64 = 0.9;
+
+// domains sort first, so that their proposals are known when looking at addresses
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum SenderKind {
+    Domain,
+    Address,
+}
+
+// a rule to add, or an existing rule whose filter could be extended
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Proposal {
+    pub kind: SenderKind,
+    pub sender: String,
+    pub folder: String,
+    pub matched: usize,
+    pub total: usize,
+    pub precision: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_rule: Option<Rule>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extend_rule: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_filter: Option<String>,
+}
+
+// a sender filed in several folders, no rule can be proposed for it
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Conflict {
+    pub kind: SenderKind,
+    pub sender: String,
+    pub folders: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct LearnReport {
+    pub proposals: Vec<Proposal>,
+    pub conflicts: Vec<Conflict>,
+}
+
+// folders messages are sorted to, as named by rule targets
+pub fn target_folders(rules_set: &RulesSet) -> Vec<String> {
+    let mut folders: Vec<String> = rules_set
+        .folders
+        .iter()
+        .flat_map(|folder| rules_set.folder_rules(folder))
+        .chain(
+            rules_set
+                .groups
+                .iter()
+                .flatten()
+                .flat_map(|group| group.rules.clone()),
+        )
+        .map(|rule| rule.target)
+        // templated targets and folders of other accounts cannot be scanned
+        .filter(|target| !target.contains('{') && !target.starts_with("account:"))
+        .collect();
+    folders.sort();
+    folders.dedup();
+    folders
+}
+
+// count, for each sender address and domain, how many messages are in each folder
+fn count_senders(
+    store: &mut dyn MailStore,
+    folders: &[String],
+) -> Result<BTreeMap<(SenderKind, String), BTreeMap<String, usize>>> {
+    let mut counts: BTreeMap<(SenderKind, String), BTreeMap<String, usize>> = BTreeMap::new();
+    for folder in folders {
+        if let Err(error) = store.open_folder(folder) {
+            log::warn!("cannot open folder {} : {}", folder, error);
+            continue;
+        }
+        let uids = store.search_uids("ALL")?;
+        let headers = store.fetch_headers(&uids, &["From"])?;
+        log::debug!("{} messages in {}", headers.len(), folder);
+        for (_, header) in headers {
+            let Some(sender) = header_value(&header, "From").map(|from| address(&from)) else {
+                continue;
+            };
+            let Some((_, host)) = sender.split_once('@') else {
+                continue;
+            };
+            let host = host.to_string();
+            for key in [(SenderKind::Address, sender), (SenderKind::Domain, host)] {
+                *counts
+                    .entry(key)
+                    .or_default()
+                    .entry(folder.clone())
+                    .or_default() += 1;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+fn sender_filter(kind: SenderKind, sender: &str) -> String {
+    match kind {
+        SenderKind::Address => format!("FROM {}", sender),
+        SenderKind::Domain => format!("FROM @{}", sender),
+    }
+}
+
+pub fn learn(
+    store: &mut dyn MailStore,
+    rules_set: &RulesSet,
+    folders: &[String],
+) -> Result<LearnReport> {
+    let counts = count_senders(store, folders)?;
+    let existing_rules: Vec<Rule> = rules_set
+        .list_folders()
+        .iter()
+        .flat_map(|folder| rules_set.rules_for_folder(folder))
+        .collect();
+
+    let mut report = LearnReport::default();
+    for ((kind, sender), folder_counts) in &counts {
+        let total: usize = folder_counts.values().sum();
+        if total < MIN_COUNT {
+            continue;
+        }
+        // folders are sorted, so the first one wins a tie
+        let (folder, matched) = folder_counts.iter().fold(("", 0), |best, (folder, count)| {
+            if *count > best.1 {
+                (folder.as_str(), *count)
+            } else {
+                best
+            }
+        });
+        let precision = matched as f64 / total as f64;
+        if precision < MIN_PRECISION {
+            report.conflicts.push(Conflict {
+                kind: *kind,
+                sender: sender.clone(),
+                folders: folder_counts.clone(),
+            });
+            continue;
+        }
+
+        // nothing to learn if a rule already knows this sender
+        let filter = sender_filter(*kind, sender);
+        let lower_sender = sender.to_lowercase();
+        if existing_rules
+            .iter()
+            .any(|rule| rule.filter.to_lowercase().contains(&lower_sender))
+        {
+            continue;
+        }
+        // an address is already covered by the proposal for its domain
+        if *kind == SenderKind::Address {
+            let domain = sender.rsplit('@').next().unwrap_or_default();
+            if report.proposals.iter().any(|proposal| {
+                proposal.kind == SenderKind::Domain
+                    && proposal.sender == domain
+                    && proposal.folder == folder
+            }) {
+                continue;
+            }
+        }
+
+        let mut proposal = Proposal {
+            kind: *kind,
+            sender: sender.clone(),
+            folder: folder.to_string(),
+            matched,
+            total,
+            precision: (precision * 1000.0).round() / 1000.0,
+            new_rule: None,
+            extend_rule: None,
+            new_filter: None,
+        };
+        // prefer extending a rule already sorting to this folder
+        match existing_rules
+            .iter()
+            .find(|rule| rule.target == folder && !rule.filter.is_empty())
+        {
+            Some(rule) => {
+                proposal.extend_rule = Some(rule.name.clone());
+                proposal.new_filter = Some(format!("OR {} ({})", filter, rule.filter));
+            }
+            None => {
+                proposal.new_rule = Some(Rule {
+                    name: format!("learned {}", sender),
+                    filter,
+                    target: folder.to_string(),
+                    enable: false,
+                    ..Default::default()
+                });
+            }
+        }
+        report.proposals.push(proposal);
+    }
+    Ok(report)
+}
+
+// write the report as YAML, to a file or to the standard output with "-"
+pub fn write_report(report: &LearnReport, path: &str) -> Result<()> {
+    let yaml = serde_yaml::to_string(report)?;
+    if path == "-" {
+        print!("{}", yaml);
+        return Ok(());
+    }
+    std::fs::write(path, yaml).with_context(|| format!("Failed to write file: {}", path))?;
+    log::info!(
+        "{} proposals and {} conflicts written to {}",
+        report.proposals.len(),
+        report.conflicts.len(),
+        path
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::FolderRule;
+
+    fn rule(target: &str) -> Rule {
+        Rule {
+            name: target.to_string(),
+            target: target.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn target_folders_are_those_of_this_account() {
+        let rules_set = RulesSet {
+            folders: vec![FolderRule {
+                folder: "INBOX".to_string(),
+                rules: vec![
+                    rule("Vendors"),
+                    rule("Lists/{list_id}"),
+                    rule("account:archive/Old"),
+                    rule("Projects"),
+                    rule("Vendors"),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(target_folders(&rules_set), vec!["Projects", "Vendors"]);
+    }
+}
@@ -0,0 +1,59 @@
+// imap_sorter moves messages of IMAP folders according to rules, this is its sorting engine
+// along with the tools the command line uses
+
+// the sorting engine and what it builds on, used through the types exported below
+mod interactive;
+mod rules;
+mod schedule;
+mod sorter;
+mod store;
+mod tags;
+mod thread;
+
+pub use account::Accounts;
+pub use classifier::{ClassifierConfig, NaiveBayes};
+pub use imap_tools::RunOptions;
+pub use interactive::Interactive;
+pub use journal::Journal;
+pub use rules::{
+    DedupeRule, FolderRule, RetentionAction, RetentionRule, Rule, RuleGroup, RulesSet,
+};
+pub use schedule::{Schedule, ScheduleState};
+pub use sorter::{FolderReport, FolderSelection, ReportFormat, RuleReport, RunReport, Sorter};
+pub use store::{HeaderAndText, MailStore, StoredMessage};
+pub use tags::TagExpression;
+
+// the settings of config.ini, also read by the examples
+pub mod config;
+
+// the modules of the command line, only what it uses is public, not part of the library API
+#[doc(hidden)]
+pub mod account;
+#[doc(hidden)]
+pub mod archive;
+#[doc(hidden)]
+pub mod classifier;
+#[doc(hidden)]
+pub mod dedupe;
+#[doc(hidden)]
+pub mod imap_tools;
+#[doc(hidden)]
+pub mod journal;
+#[doc(hidden)]
+pub mod learn;
+#[doc(hidden)]
+pub mod lists;
+#[doc(hidden)]
+pub mod logging;
+#[doc(hidden)]
+pub mod managesieve;
+#[doc(hidden)]
+pub mod metrics;
+#[doc(hidden)]
+pub mod sieve;
+#[doc(hidden)]
+pub mod suggest;
+#[doc(hidden)]
+pub mod thunderbird;
+#[doc(hidden)]
+pub mod tui;
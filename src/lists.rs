@@ -0,0 +1,93 @@
+use crate::imap_tools::header_value;
+use crate::store::MailStore;
+use chrono::{DateTime, FixedOffset};
+use std::collections::HashMap;
+
+// identifier of a mailing list, the part between angle brackets of List-Id (rfc2919)
+pub(crate) fn list_id(value: &str) -> String {
+    match value.rsplit_once('<') {
+        Some((_, id)) => id.trim_end_matches('>').trim().to_string(),
+        None => value.trim().to_string(),
+    }
+}
+
+// fetch List-Id of messages, as a list of (uid, list id)
+pub(crate) fn fetch_list_ids(
+    store: &mut dyn MailStore,
+    uids: &[u32],
+) -> anyhow::Result<Vec<(u32, Option<String>)>> {
+    let messages = store.fetch_headers(uids, &["List-Id"])?;
+    Ok(messages
+        .into_iter()
+        .map(|(uid, header)| {
+            let list = header_value(&header, "List-Id").map(|value| list_id(&value));
+            (uid, list)
+        })
+        .collect())
+}
+
+#[derive(Debug)]
+pub struct ListStats {
+    pub list_id: String,
+    pub count: usize,
+    pub last_seen: Option<DateTime<FixedOffset>>,
+    pub unsubscribe: Option<String>,
+}
+
+// every mailing list found in a folder, most frequent first
+pub fn list_report(store: &mut dyn MailStore, folder: &str) -> anyhow::Result<Vec<ListStats>> {
+    store.open_folder(folder)?;
+    let uids = store.search_uids("ALL")?;
+    let headers = store.fetch_headers(&uids, &["List-Id", "List-Unsubscribe"])?;
+    let dates: HashMap<u32, DateTime<FixedOffset>> =
+        store.fetch_dates(&uids)?.into_iter().collect();
+
+    let mut lists: HashMap<String, ListStats> = HashMap::new();
+    for (uid, header) in headers {
+        let Some(value) = header_value(&header, "List-Id") else {
+            continue;
+        };
+        let id = list_id(&value);
+        let stats = lists.entry(id.clone()).or_insert(ListStats {
+            list_id: id,
+            count: 0,
+            last_seen: None,
+            unsubscribe: None,
+        });
+        stats.count += 1;
+        let date = dates.get(&uid).copied();
+        if date > stats.last_seen {
+            stats.last_seen = date;
+        }
+        if stats.unsubscribe.is_none() {
+            stats.unsubscribe = header_value(&header, "List-Unsubscribe");
+        }
+    }
+
+    let mut lists: Vec<ListStats> = lists.into_values().collect();
+    lists.sort_by(|a, b| b.count.cmp(&a.count).then(a.list_id.cmp(&b.list_id)));
+    Ok(lists)
+}
+
+pub fn print_list_report(folder: &str, lists: &[ListStats]) {
+    println!("mailing lists in {}", folder);
+    println!(
+        "{list:<50} {count:>6} {last:<12} unsubscribe",
+        list = "list-id",
+        count = "count",
+        last = "last seen"
+    );
+    for stats in lists {
+        let last_seen = match stats.last_seen {
+            Some(date) => date.format("%Y-%m-%d").to_string(),
+            None => "-".to_string(),
+        };
+        println!(
+            "{list:<50} {count:>6} {last:<12} {unsubscribe}",
+            list = stats.list_id,
+            count = stats.count,
+            last = last_seen,
+            unsubscribe = stats.unsubscribe.as_deref().unwrap_or("-")
+        );
+    }
+}
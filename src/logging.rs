@@ -0,0 +1,146 @@
+use log::kv::{Key, Value, VisitSource, VisitValue};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+// log output for pipelines: one json object per event, and a log file rotated by size
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// log file moved to FILE.1, FILE.2, ... once it would grow over its maximum size
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    // rotated files kept along with the current one
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &str, max_size: u64, keep: usize) -> std::io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: PathBuf::from(path),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            // the oldest file is overwritten
+            for index in (1..self.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    std::fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// tells if a value is empty, like a field given as None
+struct IsNull(bool);
+
+impl<'v> VisitValue<'v> for IsNull {
+    fn visit_any(&mut self, _: Value) -> Result<(), log::kv::Error> {
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), log::kv::Error> {
+        self.0 = true;
+        Ok(())
+    }
+}
+
+// key values of a log event, numbers kept as numbers and uid sets as arrays, empty ones left out
+struct JsonFields(serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let mut is_null = IsNull(false);
+        value.visit(&mut is_null)?;
+        if is_null.0 {
+            return Ok(());
+        }
+        let json = if let Some(number) = value.to_u64() {
+            serde_json::Value::from(number)
+        } else if let Some(number) = value.to_i64() {
+            serde_json::Value::from(number)
+        } else if let Some(boolean) = value.to_bool() {
+            serde_json::Value::from(boolean)
+        } else if key.as_str() == "uids" {
+            let uids: Vec<u32> = value
+                .to_string()
+                .split(',')
+                .filter_map(|uid| uid.trim().parse().ok())
+                .collect();
+            serde_json::Value::from(uids)
+        } else {
+            serde_json::Value::from(value.to_string())
+        };
+        self.0.insert(key.as_str().to_string(), json);
+        Ok(())
+    }
+}
+
+// one log event as a json object, with the fields given along the message
+pub fn json_line(record: &log::Record) -> String {
+    let mut fields = JsonFields(serde_json::Map::new());
+    fields.0.insert(
+        "timestamp".to_string(),
+        chrono::Local::now()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+            .into(),
+    );
+    fields.0.insert(
+        "level".to_string(),
+        record.level().as_str().to_lowercase().into(),
+    );
+    fields
+        .0
+        .insert("message".to_string(), record.args().to_string().into());
+    // the visitor never fails
+    let _ = record.key_values().visit(&mut fields);
+    serde_json::Value::Object(fields.0).to_string()
+}
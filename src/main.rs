@@ -1,21 +1,21 @@
-extern crate imap;
-#[cfg(feature = "securestore")]
-extern crate securestore;
-#[cfg(feature = "securestore")]
-use std::path::Path;
 // cli
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{ArgGroup, Args as ClapArgs, CommandFactory, Parser, Subcommand};
 
 // log
 use std::io::Write;
 
-mod config;
-mod imap_tools;
-mod rules;
-use crate::imap_tools::*;
-// use crate::rules::Rule;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use clap;
+use anyhow::Context;
+use imap_sorter::imap_tools::*;
+use imap_sorter::{
+    account, archive, classifier, config, dedupe, journal, learn, lists, logging, managesieve,
+    metrics, sieve, suggest, thunderbird, tui, DedupeRule, FolderSelection, Interactive,
+    ReportFormat, RulesSet, RunReport, ScheduleState, Sorter, TagExpression,
+};
+// use crate::rules::Rule;
 
 #[derive(Parser, Default, Debug)]
 #[clap(
@@ -24,42 +24,378 @@ use clap;
     version = "1.0.0",
     about = "Process email in IMAP Inbox according to rules"
 )]
+// without a command folders are sorted like with run, the former list flags are kept hidden
+// for one release, one at a time and only without a command
+#[clap(group(ArgGroup::new("list").multiple(false)))]
 struct Args {
     #[clap(
         short,
         long,
+        global = true,
         default_value = "config.ini",
         help = "where to find config file"
     )]
     config: String,
-    #[clap(short, long, help = "where to file rule YAML file")]
+    #[clap(short, long, global = true, help = "where to file rule YAML file")]
     rules: Option<String>,
-    #[clap(short, long, help = "do not move message (aka simlation mode)")]
-    nomove: bool,
-    #[clap(short, long, help = "force, execute all rules, even disabled one")]
-    force: bool,
-    #[clap(short, long, help = "no output")]
+    #[clap(short, long, global = true, help = "no output")]
     silent: bool,
-    #[clap(short, long, help = "more details about what is going on")]
+    #[clap(
+        short,
+        long,
+        global = true,
+        help = "more details about what is going on"
+    )]
     verbose: bool,
-    #[clap(short, long, help = "much more details about what is going on")]
-    debug: bool,
     #[clap(
         short,
         long,
-        help = "filter by this tag, only rule matching this tag will be executed"
+        global = true,
+        help = "much more details about what is going on"
     )]
-    tag: Option<String>,
-    #[clap(short = 'F', long, help = "limit to processe only designated folder")]
-    folder: Option<String>,
-    #[clap(short, long, help = "apply rules assigned to INBOX on provided folder")]
-    onfolder: Option<String>,
-    #[clap(long, help = "list all rules")]
+    debug: bool,
+    #[clap(long, hide = true, group = "list", help = "list all rules")]
     listrules: bool,
-    #[clap(long, help = "list all tags")]
+    #[clap(long, hide = true, group = "list", help = "list all tags")]
     listtags: bool,
-    #[clap(long, help = "list all folders")]
+    #[clap(long, hide = true, group = "list", help = "list all folders")]
     listfolders: bool,
+    #[clap(
+        long,
+        value_enum,
+        global = true,
+        default_value = "text",
+        help = "text, or json with one object per event"
+    )]
+    log_format: logging::LogFormat,
+    #[clap(
+        long,
+        value_name = "FILE",
+        global = true,
+        help = "write logs to this file instead of the standard error, rotated by size"
+    )]
+    log_file: Option<String>,
+    #[clap(
+        long,
+        global = true,
+        default_value = "10485760",
+        help = "with --log-file, size in bytes above which the file is rotated"
+    )]
+    log_max_size: u64,
+    #[clap(
+        long,
+        global = true,
+        default_value = "5",
+        help = "with --log-file, rotated files to keep"
+    )]
+    log_keep: usize,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[clap(about = "sort folders with their rules")]
+    Run(RunArgs),
+    #[clap(about = "list or check the rules")]
+    Rules {
+        #[clap(subcommand)]
+        command: RulesCommand,
+    },
+    #[clap(about = "list all tags")]
+    Tags,
+    #[clap(about = "list all folders, patterns resolved against the server")]
+    Folders,
+    #[clap(about = "move back the messages of the last moves recorded in the journal")]
+    Undo(UndoArgs),
+    #[clap(about = "keep running, sorting again every --interval seconds")]
+    Daemon(DaemonArgs),
+    #[clap(about = "count what the journal recorded, by action, rule and target")]
+    Stats(StatsArgs),
+    #[clap(about = "browse folders and messages, and create rules from messages")]
+    Tui,
+    #[clap(
+        about = "only move away duplicated messages, of folders with a dedupe section or of --folder"
+    )]
+    Dedupe(DedupeArgs),
+    #[clap(about = "list mailing lists found in INBOX, or in --folder, with message counts")]
+    Lists(FolderArgs),
+    #[clap(
+        about = "suggest rules from messages of INBOX, or of --folder, grouped by sender, list and subject"
+    )]
+    Suggest(FolderArgs),
+    #[clap(about = "learn rules from folders messages are already filed in")]
+    Learn(LearnArgs),
+    #[clap(about = "train the classifier on the folders of each class, and save its model")]
+    Train,
+    #[clap(about = "print the class the classifier gives to messages of INBOX, or of --folder")]
+    Classify(FolderArgs),
+    #[clap(about = "export, import or upload rules as a Sieve script")]
+    Sieve {
+        #[clap(subcommand)]
+        command: SieveCommand,
+    },
+    #[clap(about = "print rules YAML translated from Thunderbird filters (msgFilterRules.dat)")]
+    Thunderbird {
+        #[clap(value_name = "FILE")]
+        file: String,
+    },
+    #[clap(about = "export messages of INBOX, or of --folder, to an mbox file or a directory")]
+    Export(ExportArgs),
+    #[clap(
+        about = "append messages of an mbox file, Maildir or .eml directory to INBOX, or to --folder, and sort them with its rules"
+    )]
+    Restore(RestoreArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum SieveCommand {
+    #[clap(about = "write INBOX rules as a Sieve script to this file (- for output)")]
+    Export {
+        #[clap(value_name = "FILE")]
+        file: String,
+    },
+    #[clap(about = "print rules YAML translated from this Sieve script")]
+    Import {
+        #[clap(value_name = "FILE")]
+        file: String,
+    },
+    #[clap(about = "upload INBOX rules as a Sieve script with ManageSieve, and make it active")]
+    Push {
+        #[clap(
+            long,
+            help = "check the script and show how it differs from the active one, without uploading it"
+        )]
+        dry_run: bool,
+    },
+}
+
+#[derive(ClapArgs, Debug)]
+struct FolderArgs {
+    #[clap(short = 'F', long, help = "folder to work on instead of INBOX")]
+    folder: Option<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct DedupeArgs {
+    #[clap(short, long, help = "do not move message (aka simlation mode)")]
+    nomove: bool,
+    #[clap(
+        short = 'F',
+        long,
+        help = "only this folder, deduplicated even without a dedupe section"
+    )]
+    folder: Option<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct LearnArgs {
+    #[clap(
+        value_name = "FILE",
+        help = "YAML file to write the proposed rules to (- for output)"
+    )]
+    file: String,
+    #[clap(long, help = "scan all folders instead of rule targets")]
+    all_folders: bool,
+}
+
+#[derive(ClapArgs, Debug)]
+struct ExportArgs {
+    #[clap(value_name = "PATH", help = "mbox file or directory to write to")]
+    path: String,
+    #[clap(short = 'F', long, help = "folder to export instead of INBOX")]
+    folder: Option<String>,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "mbox",
+        help = "format of the export"
+    )]
+    format: archive::ArchiveFormat,
+    #[clap(
+        long,
+        value_name = "SEARCH",
+        help = "only messages matching this IMAP search"
+    )]
+    filter: Option<String>,
+    #[clap(long, value_name = "RULE", help = "only messages matched by this rule")]
+    rule: Option<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct RestoreArgs {
+    #[clap(
+        value_name = "PATH",
+        help = "mbox file, Maildir or .eml directory to append"
+    )]
+    path: String,
+    #[clap(short = 'F', long, help = "folder to append to instead of INBOX")]
+    folder: Option<String>,
+    #[clap(short, long, help = "do not move message (aka simlation mode)")]
+    nomove: bool,
+    #[clap(short, long, help = "force, execute all rules, even disabled one")]
+    force: bool,
+    #[clap(
+        short,
+        long,
+        value_name = "EXPRESSION",
+        help = "only rules whose tags match this expression"
+    )]
+    tag: Option<TagExpression>,
+}
+
+#[derive(Subcommand, Debug)]
+enum RulesCommand {
+    #[clap(about = "list all rules, or those selected by --tag")]
+    List {
+        #[clap(
+            short,
+            long,
+            value_name = "EXPRESSION",
+            help = "only rules whose tags match this expression, like 'daily & !slow'"
+        )]
+        tag: Option<TagExpression>,
+    },
+    #[clap(about = "check the rules for mistakes, without connecting to the server")]
+    Check,
+}
+
+#[derive(ClapArgs, Default, Debug)]
+struct RunArgs {
+    #[clap(short, long, help = "do not move message (aka simlation mode)")]
+    nomove: bool,
+    #[clap(short, long, help = "force, execute all rules, even disabled one")]
+    force: bool,
+    #[clap(
+        short,
+        long,
+        value_name = "EXPRESSION",
+        help = "only rules whose tags match this expression, like 'daily & !slow' or 'newsletter | promo'"
+    )]
+    tag: Option<TagExpression>,
+    #[clap(
+        long,
+        value_name = "FOLDER",
+        conflicts_with_all = ["apply_rules_of", "on"],
+        help = "sort only this folder with its own rules, may be repeated or an IMAP LIST pattern"
+    )]
+    only_folder: Vec<String>,
+    #[clap(
+        long,
+        value_name = "FOLDER",
+        requires = "on",
+        help = "apply the rules of this folder on the folders of --on, may be repeated or an IMAP LIST pattern"
+    )]
+    apply_rules_of: Vec<String>,
+    #[clap(
+        short,
+        long,
+        alias = "onfolder",
+        value_name = "FOLDER",
+        help = "sort this folder with the rules of --apply-rules-of (INBOX by default), may be repeated or an IMAP LIST pattern"
+    )]
+    on: Vec<String>,
+    #[clap(long, help = "allow retention rules to delete messages")]
+    allow_delete: bool,
+    #[clap(long, help = "run rules with a schedule even when they are not due")]
+    ignore_schedule: bool,
+    #[clap(
+        long,
+        help = "list the messages each rule matched, and ask before moving all, some or none of them"
+    )]
+    interactive: bool,
+    #[clap(
+        long,
+        value_enum,
+        help = "print what each rule did once sorted, as text or json"
+    )]
+    report: Option<ReportFormat>,
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "with --report, write the report to this file instead of printing it"
+    )]
+    report_file: Option<String>,
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "write Prometheus metrics to this file after each run, for the node_exporter textfile collector"
+    )]
+    metrics_file: Option<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct DaemonArgs {
+    #[clap(flatten)]
+    run: RunArgs,
+    #[clap(long, default_value = "300", help = "seconds between runs")]
+    interval: u64,
+    #[clap(
+        long,
+        value_name = "ADDRESS",
+        help = "serve Prometheus metrics on http://ADDRESS/metrics, like 127.0.0.1:9898"
+    )]
+    metrics_listen: Option<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct UndoArgs {
+    #[clap(
+        long,
+        default_value = "1",
+        help = "journal entries to undo, latest first"
+    )]
+    count: usize,
+    #[clap(short, long, help = "only show what would be moved back")]
+    nomove: bool,
+}
+
+#[derive(ClapArgs, Debug)]
+struct StatsArgs {
+    #[clap(
+        long,
+        value_name = "DATE",
+        help = "only entries recorded since this date, like 2024-05-01"
+    )]
+    since: Option<chrono::NaiveDate>,
+}
+
+impl Args {
+    // the command to run, run itself when none is given
+    fn take_command(&mut self) -> Result<Command, clap::Error> {
+        let deprecated = [
+            (self.listrules, "--listrules", "rules list"),
+            (self.listtags, "--listtags", "tags"),
+            (self.listfolders, "--listfolders", "folders"),
+        ];
+        for (used, flag, command) in deprecated {
+            if !used {
+                continue;
+            }
+            if self.command.is_some() {
+                return Err(Args::command().error(
+                    ErrorKind::ArgumentConflict,
+                    format!("{} cannot be used with a command", flag),
+                ));
+            }
+            log::warn!("{} is deprecated, use the {} command", flag, command);
+        }
+        if self.listrules {
+            return Ok(Command::Rules {
+                command: RulesCommand::List { tag: None },
+            });
+        }
+        if self.listtags {
+            return Ok(Command::Tags);
+        }
+        if self.listfolders {
+            return Ok(Command::Folders);
+        }
+        Ok(self
+            .command
+            .take()
+            .unwrap_or_else(|| Command::Run(RunArgs::default())))
+    }
 }
 
 fn setup_logging(args: &Args) {
@@ -76,9 +412,10 @@ fn setup_logging(args: &Args) {
     } else {
         log::LevelFilter::Info
     };
-    env_logger::builder()
-        .filter_level(logfilter)
-        .format(|buf, record| {
+    let mut builder = env_logger::builder();
+    builder.filter_level(logfilter);
+    match args.log_format {
+        logging::LogFormat::Text => builder.format(|buf, record| {
             // we make the "info" or "debug" logging be straight output
             if record.level() == log::Level::Info || record.level() == log::Level::Debug {
                 writeln!(buf, "{}", record.args())
@@ -86,132 +423,843 @@ fn setup_logging(args: &Args) {
                 // otherwise print with log level information
                 writeln!(buf, "{}: {}", record.level(), record.args())
             }
+        }),
+        logging::LogFormat::Json => {
+            builder.format(|buf, record| writeln!(buf, "{}", logging::json_line(record)))
+        }
+    };
+    if let Some(path) = &args.log_file {
+        let file = logging::RotatingFile::open(path, args.log_max_size, args.log_keep)
+            .expect("cannot open log file");
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    builder.init();
+}
+
+// check the script generated from rules, then upload and activate it, or only show the changes
+fn push_sieve(
+    config: &config::Configuration,
+    rules_set: &RulesSet,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let (script, unsupported) = sieve::export_sieve(rules_set);
+    for (name, reason) in unsupported {
+        log::warn!("rule {} not exported: {}", name, reason);
+    }
+    let (server, _, username, password) = account::credentials(config, None)?;
+    let mut client = managesieve::connect(
+        &server,
+        config.sieve_port,
+        config.sieve_tls,
+        &username,
+        &password,
+    )?;
+    // CHECKSCRIPT came with rfc5804, older servers only check on PUTSCRIPT
+    if client.has_capability("VERSION") {
+        client.check_script(&script)?;
+    }
+    if dry_run {
+        let active = client
+            .list_scripts()?
+            .into_iter()
+            .find(|(_, active)| *active);
+        let current = match &active {
+            Some((name, _)) => client.get_script(name)?,
+            None => String::new(),
+        };
+        match active {
+            Some((name, _)) => log::info!("changes to active script {}:", name),
+            None => log::info!("no active script, {} would be:", config.sieve_script),
+        }
+        print!("{}", managesieve::diff_lines(&current, &script));
+    } else {
+        client.put_script(&config.sieve_script, &script)?;
+        client.set_active(&config.sieve_script)?;
+        log::info!("Sieve script {} uploaded and active", config.sieve_script);
+    }
+    client.logout()?;
+    Ok(())
+}
+
+// mailing lists found in a folder, with their message counts
+fn report_lists(imap_session: &mut account::Session, folder_name: &str) -> anyhow::Result<()> {
+    let lists = lists::list_report(imap_session, folder_name)
+        .context("cannot scan folder for mailing lists")?;
+    lists::print_list_report(folder_name, &lists);
+    Ok(())
+}
+
+// rules proposed for the messages of a folder, grouped by sender, list and subject
+fn suggest_rules(imap_session: &mut account::Session, folder_name: &str) -> anyhow::Result<()> {
+    let clusters = suggest::find_clusters(imap_session, folder_name)
+        .context("cannot scan folder for suggestions")?;
+    suggest::print_suggestions(folder_name, &clusters);
+    Ok(())
+}
+
+// rules learnt from the folders messages are filed in, written to the file of the command
+fn learn_rules(
+    imap_session: &mut account::Session,
+    rules_set: &RulesSet,
+    learn: &LearnArgs,
+) -> anyhow::Result<()> {
+    let folders = if learn.all_folders {
+        let (mut mailboxes, _) =
+            list_mailboxes(imap_session).context("cannot list folders on IMAP server")?;
+        // messages still in INBOX have not been filed
+        mailboxes.retain(|mailbox| !mailbox.eq_ignore_ascii_case("INBOX"));
+        mailboxes
+    } else {
+        learn::target_folders(rules_set)
+    };
+    let report = learn::learn(imap_session, rules_set, &folders)
+        .context("cannot scan folders to learn rules")?;
+    learn::write_report(&report, &learn.file)
+}
+
+// train the classifier on the folders of its classes, and save its model
+fn train_classifier(
+    imap_session: &mut account::Session,
+    rules_set: &RulesSet,
+) -> anyhow::Result<()> {
+    let Some(classifier_config) = &rules_set.classifier else {
+        anyhow::bail!("no classifier configured in rules");
+    };
+    let model = classifier::train(imap_session, classifier_config)
+        .context("cannot fetch messages to train classifier")?;
+    model.save(&classifier_config.model)?;
+    log::info!("model saved to {}", classifier_config.model);
+    Ok(())
+}
+
+// folders to sort, --on alone applies the rules of INBOX
+fn folder_selection(run: &RunArgs) -> FolderSelection {
+    if !run.only_folder.is_empty() {
+        return FolderSelection::Only(run.only_folder.clone());
+    }
+    if run.on.is_empty() {
+        return FolderSelection::All;
+    }
+    let rules_of = if run.apply_rules_of.is_empty() {
+        vec!["INBOX".to_string()]
+    } else {
+        run.apply_rules_of.clone()
+    };
+    FolderSelection::RulesOf {
+        rules_of,
+        on: run.on.clone(),
+    }
+}
+
+// a failed dedupe is logged like a failed rule, the run goes on
+fn dedupe_or_log(
+    imap_session: &mut account::Session,
+    folder_name: &str,
+    dedupe: &DedupeRule,
+    options: &RunOptions,
+) {
+    if let Err(error) = dedupe::dedupe_folders(imap_session, folder_name, dedupe, options) {
+        log::error!(
+            folder = folder_name,
+            rule = "dedupe",
+            action = "error";
+            "dedupe of {} failed: {:#}",
+            folder_name,
+            error
+        );
+    }
+}
+
+// apply the rules of each selected folder, then remove duplicates and apply retention rules
+// of the folders sorted with their own rules
+fn sort_folders(
+    run: &RunArgs,
+    rules_set: &RulesSet,
+    options: &RunOptions,
+    imap_session: &mut account::Session,
+) -> RunReport {
+    let sorter = Sorter::new(rules_set, options).with_tag(run.tag.clone());
+    let mut report = RunReport::new();
+    let selection = folder_selection(run);
+    // patterns are matched against the folders existing on the server
+    let (mailboxes, delimiter) = if selection.has_patterns() {
+        list_mailboxes(imap_session).unwrap_or_else(|error| {
+            log::error!("cannot list folders on IMAP server: {}", error);
+            (Vec::new(), "/".to_string())
         })
-        .init();
+    } else {
+        (Vec::new(), "/".to_string())
+    };
+    for (rules_folder, folder_name) in selection.resolve(rules_set, &mailboxes, &delimiter) {
+        // quitting stops the run
+        if options
+            .interactive
+            .is_some_and(|interactive| interactive.has_quit())
+        {
+            break;
+        }
+        if rules_folder != folder_name {
+            log::info!(
+                "Applying rules of {} on folder: {}",
+                rules_folder,
+                folder_name
+            );
+            report.push(sorter.apply_rules_of(imap_session, &rules_folder, &folder_name));
+            continue;
+        }
+        report.push(sorter.sort_folder(imap_session, &folder_name));
+        // duplicates and retention move messages without asking, so they wait for a run
+        // without --interactive, and do not run once quit was answered
+        if let Some(interactive) = options.interactive {
+            if interactive.has_quit() {
+                break;
+            }
+            if rules_set.dedupe_for_folder(&folder_name).is_some()
+                || !rules_set.retention_for_folder(&folder_name).is_empty()
+            {
+                log::info!(
+                    "duplicates and retention of {} skipped with --interactive",
+                    folder_name
+                );
+            }
+            continue;
+        }
+        // remove duplicates left after sorting
+        if let Some(dedupe) = rules_set.dedupe_for_folder(&folder_name) {
+            dedupe_or_log(imap_session, &folder_name, &dedupe, options);
+        }
+        // once sorted, clean up what is too old
+        let retentions = rules_set.retention_for_folder(&folder_name);
+        apply_retention_on_folder(imap_session, retentions, &folder_name, options)
+    }
+    report.finish();
+    report
+}
+
+// failing to write metrics should not stop sorting
+fn write_metrics(metrics: &Mutex<metrics::Metrics>, run: &RunArgs) {
+    if let Some(path) = &run.metrics_file {
+        if let Err(error) = metrics.lock().unwrap().write_textfile(path) {
+            log::error!("{:#}", error);
+        }
+    }
+}
+
+// journal counts as a table
+fn print_stats(stats: &std::collections::BTreeMap<(String, String, String), (usize, usize)>) {
+    println!(
+        "{:<10} {:<30} {:<30} {:>8} {:>8}",
+        "action", "rule", "target", "entries", "messages"
+    );
+    for ((action, rule, target), (entries, messages)) in stats {
+        println!(
+            "{:<10} {:<30} {:<30} {:>8} {:>8}",
+            action, rule, target, entries, messages
+        );
+    }
 }
 
 fn main() {
     // let's get the argument we are called with
-    let args = Args::parse();
+    let mut args = Args::parse();
 
     setup_logging(&args);
+    let command = match args.take_command() {
+        Ok(command) => command,
+        Err(error) => error.exit(),
+    };
 
-    let config: config::Configuration = match confy::load_path(args.config) {
+    let config: config::Configuration = match confy::load_path(&args.config) {
         Ok(config) => config,
         Err(err) => {
             panic!("Failed to load configuration: {}", err);
         }
     };
-    let rules_path = match args.rules {
+    // if only import a Sieve script, then print its rules and exit
+    if let Command::Sieve {
+        command: SieveCommand::Import { file: sieve_path },
+    } = &command
+    {
+        let imported =
+            sieve::read_sieve_file(sieve_path).and_then(|script| sieve::import_sieve(&script));
+        match imported {
+            Ok((rules_set, reports)) => {
+                for (name, report) in reports {
+                    log::warn!("{}: {}", name, report);
+                }
+                print!("{}", serde_yaml::to_string(&rules_set).unwrap());
+            }
+            Err(error) => log::error!("{:#}", error),
+        }
+        return;
+    };
+
+    // if only import Thunderbird filters, then print their rules and exit
+    if let Command::Thunderbird { file: filters_path } = &command {
+        match thunderbird::read_filters_file(filters_path) {
+            Ok(content) => {
+                let (rules_set, reports) = thunderbird::import_thunderbird(&content);
+                for (name, report) in reports {
+                    log::warn!("{}: {}", name, report);
+                }
+                print!("{}", serde_yaml::to_string(&rules_set).unwrap());
+            }
+            Err(error) => log::error!("{:#}", error),
+        }
+        return;
+    };
+
+    // if only count what the journal recorded, then print the table and exit
+    if let Command::Stats(stats) = &command {
+        let journal = journal::Journal::new(&config.journal_path);
+        match journal.entries() {
+            Ok(entries) => print_stats(&journal::stats(&entries, stats.since)),
+            Err(error) => log::error!("{:#}", error),
+        }
+        return;
+    };
+
+    let rules_path = match args.rules.clone() {
         Some(path) => path,
-        None => config.rules_conf_path,
+        None => config.rules_conf_path.clone(),
     };
 
     log::debug!("rules path: {}", rules_path);
 
-    let rules_set = match rules::RulesSet::load(rules_path.as_str()) {
+    // if only check rules, then print their problems and exit, failing if there is any
+    if let Command::Rules {
+        command: RulesCommand::Check,
+    } = &command
+    {
+        let rules_set = match RulesSet::load(rules_path.as_str()) {
+            Ok(rules_set) => rules_set,
+            Err(error) => {
+                println!("cannot read rules : {:#}", error);
+                std::process::exit(1);
+            }
+        };
+        let accounts: Vec<String> = config.accounts.keys().cloned().collect();
+        let problems = rules_set.lint(&accounts);
+        if problems.is_empty() {
+            println!("rules ok");
+            return;
+        }
+        for (rule, problem) in problems {
+            println!("{}: {}", rule, problem);
+        }
+        std::process::exit(1);
+    };
+
+    let mut rules_set = match RulesSet::load(rules_path.as_str()) {
         Ok(rules_set) => rules_set,
         Err(error) => panic!("cannot read rules : {}", error),
     };
 
     // if only list rules, then only liste rules and exit
-    if args.listrules {
-        rules_set.print();
+    if let Command::Rules {
+        command: RulesCommand::List { tag },
+    } = &command
+    {
+        rules_set.print(tag);
         return;
     };
 
     // if only list tags, then only liste tags and exit
-    if args.listtags {
+    if let Command::Tags = &command {
         println!("tags : {}", rules_set.list_tags().join(", "));
         return;
     };
 
-    // if only list folders, then only list folders and exit
-    if args.listfolders {
-        println!("folders : {}", rules_set.list_folders().join(", "));
+    // if only export to Sieve, then write the script and exit
+    if let Command::Sieve {
+        command: SieveCommand::Export { file: output },
+    } = &command
+    {
+        let (script, unsupported) = sieve::export_sieve(&rules_set);
+        for (name, reason) in unsupported {
+            log::warn!("rule {} not exported: {}", name, reason);
+        }
+        if output == "-" {
+            print!("{}", script);
+        } else if let Err(error) = std::fs::write(output, script) {
+            log::error!("cannot write {}: {}", output, error);
+        }
         return;
     };
 
-    // connect to secret manager
-    let key_file = Path::new(config.key_path.as_str());
-    let secret_manager = securestore::SecretsManager::load(
-        config.secure_store_path,
-        securestore::KeySource::File(key_file),
-    )
-    .expect("Failed to load SecureStore vault!");
-
-    // connecting to IMAP server, using parameter from vault (config.json) if exsit if not try config.ini
-    let domain = match secret_manager.get("imap_server") {
-        Ok(hostname) => hostname,
-        Err(_) => config.imap_server,
-    };
-    let domain = domain.as_str();
-    let port: u16 = config.imap_port;
-    let username = match secret_manager.get("imap_username") {
-        Ok(username) => username,
-        Err(_) => config.imap_username,
+    // if only push rules to the server Sieve, then upload the script and exit
+    if let Command::Sieve {
+        command: SieveCommand::Push { dry_run },
+    } = &command
+    {
+        if let Err(error) = push_sieve(&config, &rules_set, *dry_run) {
+            log::error!("{:#}", error);
+        }
+        return;
     };
-    let password = match secret_manager.get("imap_password") {
-        Ok(password) => password,
-        Err(_) => config.imap_password.clone(),
+
+    // if only list folders without patterns, then only list folders and exit
+    if let (Command::Folders, false) = (&command, rules_set.has_folder_patterns()) {
+        println!("folders : {}", rules_set.list_folders().join(", "));
+        return;
     };
 
-    // we pass in the domain twice to check that the server's TLS
-    // certificate is valid for the domain we're connecting to.
-    let client = match imap::ClientBuilder::new(domain, port).connect() {
-        Ok(client) => client,
+    let mut imap_session = match account::connect(&config, None) {
+        Ok(imap_session) => imap_session,
         Err(error) => {
-            log::error!("Error with IMAP server : {}", error);
+            log::error!("{}", error);
             return;
         }
     };
 
-    // the client we have here is unauthenticated.
-    // to do anything useful with the e-mails, we need to log in
-    let mut imap_session = client
-        .login(username, password)
-        .map_err(|e| e.0)
-        .expect("cannot connect to IMAP server");
-
-    match args.folder {
-        // have been provided with a folder,
-        Some(folder_name) => {
-            let inbox = "INBOX".to_string();
-            let rules = rules_set.rules_for_folder(&inbox);
-            log::info!("Applying rules for {} on folder: {}", inbox, folder_name);
-            apply_rules_on_folder(
-                &mut imap_session,
-                rules,
-                &folder_name,
-                &args.tag,
-                args.nomove,
-                args.force,
-            )
-        }
-        // let's do if for all folders
-        None => {
-            // now for each rules we find message and moved them as necessary
-            for folder_name in rules_set.list_folders() {
-                // test if we have a folder filter
-                if let Some(folder_filter) = args.folder.as_deref() {
-                    // skip this iteration if we do not match filter
-                    if !folder_name.eq_ignore_ascii_case(folder_filter) {
-                        log::debug!("-------------------- Skipping folder : {}", &folder_name);
-                        continue;
+    // if only undo the last moves, then move their messages back and exit
+    if let Command::Undo(undo) = &command {
+        let journal = journal::Journal::new(&config.journal_path);
+        match journal.entries() {
+            Ok(entries) => {
+                for entry in journal::entries_to_undo(&entries, undo.count) {
+                    match journal::undo_entry(&mut imap_session, &journal, &entry, undo.nomove) {
+                        Ok(count) => log::info!(
+                            "{} messages of rule {} moved back to {}",
+                            count,
+                            entry.rule,
+                            entry.folder
+                        ),
+                        Err(error) => log::warn!("{:#}", error),
                     }
                 }
-                let rules = rules_set.rules_for_folder(&folder_name);
-                apply_rules_on_folder(
+            }
+            Err(error) => log::error!("{:#}", error),
+        }
+        imap_session.logout().expect("failed to logout");
+        return;
+    };
+
+    // folder patterns are resolved against the folders existing on the server
+    if rules_set.has_folder_patterns() {
+        match list_mailboxes(&mut imap_session) {
+            Ok((mailboxes, delimiter)) => rules_set.resolve_folders(&mailboxes, &delimiter),
+            Err(error) => {
+                log::error!("cannot list folders on IMAP server: {}", error);
+                imap_session.logout().expect("failed to logout");
+                return;
+            }
+        }
+    }
+
+    // if browsing in the terminal, then run the interface until it quits and exit
+    if let Command::Tui = &command {
+        // logs would be written over the screen
+        let level = log::max_level();
+        if args.log_file.is_none() {
+            log::set_max_level(log::LevelFilter::Off);
+        }
+        let result = list_mailboxes(&mut imap_session)
+            .context("cannot list folders on IMAP server")
+            .and_then(|(mailboxes, delimiter)| {
+                tui::run(
                     &mut imap_session,
-                    rules,
-                    &folder_name,
-                    &args.tag,
-                    args.nomove,
-                    args.force,
+                    mailboxes,
+                    &delimiter,
+                    rules_set,
+                    &rules_path,
                 )
+            });
+        if let Err(error) = result {
+            log::set_max_level(level);
+            log::error!("{:#}", error);
+        }
+        imap_session.logout().expect("failed to logout");
+        return;
+    };
+
+    // if only list folders, then list resolved folders and exit
+    if let Command::Folders = &command {
+        println!("folders : {}", rules_set.list_folders().join(", "));
+        imap_session.logout().expect("failed to logout");
+        return;
+    };
+
+    // if only export messages, then write them and exit
+    if let Command::Export(export) = &command {
+        let path = &export.path;
+        let folder_name = export.folder.clone().unwrap_or("INBOX".to_string());
+        let filter = match (&export.rule, &export.filter) {
+            (Some(rule_name), _) => match rules_set.find_rule(rule_name) {
+                // the classifier is not applied here, its search alone would export every message
+                Some(rule) if rule.classifier.is_some() => {
+                    log::error!(
+                        "rule {} uses a classifier, it cannot select messages to export",
+                        rule_name
+                    );
+                    imap_session.logout().expect("failed to logout");
+                    return;
+                }
+                Some(rule) => rule.search_filter(),
+                None => {
+                    log::error!("no rule named {}", rule_name);
+                    imap_session.logout().expect("failed to logout");
+                    return;
+                }
+            },
+            (None, Some(filter)) => filter.clone(),
+            (None, None) => "ALL".to_string(),
+        };
+        match archive::export_folder(
+            &mut imap_session,
+            &folder_name,
+            &filter,
+            export.format,
+            path,
+        ) {
+            Ok(count) => log::info!("{} messages exported to {}", count, path),
+            Err(error) => log::error!("{:#}", error),
+        }
+        imap_session.logout().expect("failed to logout");
+        return;
+    };
+
+    // if only report mailing lists, then scan folder and exit
+    if let Command::Lists(lists) = &command {
+        let folder_name = lists.folder.clone().unwrap_or("INBOX".to_string());
+        if let Err(error) = report_lists(&mut imap_session, &folder_name) {
+            log::error!("{:#}", error);
+        }
+        imap_session.logout().expect("failed to logout");
+        return;
+    };
+
+    // if only suggest rules, then scan folder and exit
+    if let Command::Suggest(suggest) = &command {
+        let folder_name = suggest.folder.clone().unwrap_or("INBOX".to_string());
+        if let Err(error) = suggest_rules(&mut imap_session, &folder_name) {
+            log::error!("{:#}", error);
+        }
+        imap_session.logout().expect("failed to logout");
+        return;
+    };
+
+    // if only learn rules, then scan filed messages and exit
+    if let Command::Learn(learn) = &command {
+        if let Err(error) = learn_rules(&mut imap_session, &rules_set, learn) {
+            log::error!("{:#}", error);
+        }
+        imap_session.logout().expect("failed to logout");
+        return;
+    };
+
+    // if only train the classifier, then train, save the model and exit
+    if let Command::Train = &command {
+        if let Err(error) = train_classifier(&mut imap_session, &rules_set) {
+            log::error!("{:#}", error);
+        }
+        imap_session.logout().expect("failed to logout");
+        return;
+    };
+
+    // the classifier model is needed to classify, or when rules use it
+    let model = match &rules_set.classifier {
+        Some(classifier_config)
+            if matches!(command, Command::Classify(_)) || rules_set.uses_classifier() =>
+        {
+            match classifier::NaiveBayes::load(&classifier_config.model) {
+                Ok(model) => Some(model),
+                Err(error) => {
+                    log::error!("{:#}", error);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    // if only classify, then print classes and exit
+    if let Command::Classify(classify) = &command {
+        if let Some(model) = &model {
+            let folder_name = classify.folder.clone().unwrap_or("INBOX".to_string());
+            let classified = classifier::classify_folder(&mut imap_session, model, &folder_name);
+            if let Err(error) = classified.context("cannot fetch messages to classify") {
+                log::error!("{:#}", error);
+            }
+        }
+        imap_session.logout().expect("failed to logout");
+        return;
+    };
+
+    let journal = journal::Journal::new(&config.journal_path);
+    let accounts = account::Accounts::new(&config);
+    let interactive = Interactive::default();
+    let options = RunOptions {
+        nomove: false,
+        force: false,
+        allow_delete: false,
+        journal: &journal,
+        model: model.as_ref(),
+        accounts: Some(&accounts),
+        schedule: None,
+        interactive: None,
+    };
+
+    // if only restore an archive, then append its messages and exit
+    if let Command::Restore(restore) = &command {
+        let folder_name = restore.folder.clone().unwrap_or("INBOX".to_string());
+        let rules = rules_set.rules_for_folder(&folder_name);
+        let options = RunOptions {
+            nomove: restore.nomove,
+            force: restore.force,
+            ..options
+        };
+        if let Err(error) = archive::restore_archive(
+            &mut imap_session,
+            &restore.path,
+            &folder_name,
+            rules,
+            &restore.tag,
+            &options,
+        ) {
+            log::error!("{:#}", error);
+        }
+        accounts.logout();
+        imap_session.logout().expect("failed to logout");
+        return;
+    };
+
+    // if only dedupe, then look for duplicates and exit
+    if let Command::Dedupe(dedupe) = &command {
+        let options = RunOptions {
+            nomove: dedupe.nomove,
+            ..options
+        };
+        let folders = match &dedupe.folder {
+            Some(folder_name) => vec![folder_name.clone()],
+            None => rules_set.list_folders(),
+        };
+        for folder_name in folders {
+            let dedupe = match rules_set.dedupe_for_folder(&folder_name) {
+                Some(dedupe) => dedupe,
+                // a folder given explicitly is deduplicated even without configuration
+                None if dedupe.folder.is_some() => DedupeRule {
+                    target: "Duplicates".to_string(),
+                    folders: None,
+                    enable: true,
+                },
+                None => continue,
+            };
+            dedupe_or_log(&mut imap_session, &folder_name, &dedupe, &options);
+        }
+        imap_session.logout().expect("failed to logout");
+        return;
+    };
+
+    // every other command is done, what is left is sorting, once or again and again
+    let (run, daemon) = match &command {
+        Command::Daemon(daemon) => (&daemon.run, Some(daemon)),
+        Command::Run(run) => (run, None),
+        _ => unreachable!("command handled above"),
+    };
+    let options = RunOptions {
+        nomove: run.nomove,
+        force: run.force,
+        allow_delete: run.allow_delete,
+        interactive: run.interactive.then_some(&interactive),
+        ..options
+    };
+
+    let metrics = Arc::new(Mutex::new(metrics::Metrics::default()));
+    if let Some(address) = daemon.and_then(|daemon| daemon.metrics_listen.as_ref()) {
+        if let Err(error) = metrics::serve(address, metrics.clone()) {
+            log::error!("{:#}", error);
+            return;
+        }
+    }
+
+    // rules with a schedule only run when due, according to when they last ran
+    // the state file is only read and written when some rule has a schedule
+    let scheduled = rules_set.scheduled_rules();
+    let mut schedule = None;
+    if !run.ignore_schedule && !config.schedule_state_path.is_empty() && !scheduled.is_empty() {
+        match ScheduleState::load(&config.schedule_state_path) {
+            Ok(state) => schedule = Some(state),
+            Err(error) => {
+                log::error!("{:#}", error);
+                return;
+            }
+        }
+    }
+
+    // only a daemon runs again, and connects again
+    let interval = Duration::from_secs(daemon.map_or(0, |daemon| daemon.interval));
+    let mut session = Some(imap_session);
+    loop {
+        // a daemon connects again for each run, the server may have closed an idle session
+        let mut imap_session = match session.take() {
+            Some(imap_session) => imap_session,
+            None => match account::connect(&config, None) {
+                Ok(imap_session) => imap_session,
+                Err(error) => {
+                    log::error!("{}", error);
+                    metrics.lock().unwrap().record_failure();
+                    write_metrics(&metrics, run);
+                    std::thread::sleep(interval);
+                    continue;
+                }
+            },
+        };
+
+        let run_options = RunOptions {
+            schedule: schedule.as_ref(),
+            ..options
+        };
+        let report = sort_folders(run, &rules_set, &run_options, &mut imap_session);
+        // a dry run does not count as a run
+        if let (Some(schedule), false) = (&mut schedule, run.nomove) {
+            schedule.record(&report, &scheduled);
+            if let Err(error) = schedule.save() {
+                log::error!("{:#}", error);
+            }
+        }
+        if let Some(format) = run.report {
+            if let Err(error) = report.write(format, run.report_file.as_deref()) {
+                log::error!("{:#}", error);
+            }
+        }
+        {
+            let mut metrics = metrics.lock().unwrap();
+            metrics.record_run(&report);
+            metrics::record_folder_sizes(
+                &mut metrics,
+                &mut imap_session,
+                &rules_set.list_folders(),
+            );
+        }
+        write_metrics(&metrics, run);
+
+        // be nice to the servers and log out
+        accounts.logout();
+        imap_session.logout().expect("failed to logout");
+        if daemon.is_none() {
+            break;
+        }
+        log::debug!("next run in {} seconds", interval.as_secs());
+        std::thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(command_line: &[&str]) -> Result<Command, clap::Error> {
+        Args::try_parse_from(std::iter::once("imap_sorter").chain(command_line.iter().copied()))?
+            .take_command()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn selection(command_line: &[&str]) -> FolderSelection {
+        match parse(command_line).unwrap() {
+            Command::Run(run) => folder_selection(&run),
+            Command::Daemon(daemon) => folder_selection(&daemon.run),
+            command => panic!("{:?} does not sort", command),
+        }
+    }
+
+    fn error_kind(command_line: &[&str]) -> Option<ErrorKind> {
+        parse(command_line).err().map(|error| error.kind())
+    }
+
+    #[test]
+    fn folders_to_sort() {
+        assert_eq!(selection(&[]), FolderSelection::All);
+        assert_eq!(selection(&["run"]), FolderSelection::All);
+        assert_eq!(
+            selection(&["run", "--only-folder", "Lists/%", "--only-folder", "INBOX"]),
+            FolderSelection::Only(strings(&["Lists/%", "INBOX"]))
+        );
+        assert_eq!(
+            selection(&["run", "--apply-rules-of", "INBOX", "--on", "Archive/*"]),
+            FolderSelection::RulesOf {
+                rules_of: strings(&["INBOX"]),
+                on: strings(&["Archive/*"]),
             }
+        );
+        // --on alone applies the rules of INBOX
+        assert_eq!(
+            selection(&["daemon", "--onfolder", "Old"]),
+            FolderSelection::RulesOf {
+                rules_of: strings(&["INBOX"]),
+                on: strings(&["Old"]),
+            }
+        );
+    }
+
+    #[test]
+    fn only_folder_conflicts_with_other_selections() {
+        let conflicts: [&[&str]; 3] = [
+            &["run", "--only-folder", "INBOX", "--on", "Old"],
+            &[
+                "run",
+                "--only-folder",
+                "INBOX",
+                "--apply-rules-of",
+                "A",
+                "--on",
+                "B",
+            ],
+            &["daemon", "--on", "Old", "--only-folder", "INBOX"],
+        ];
+        for command_line in conflicts {
+            assert_eq!(
+                error_kind(command_line),
+                Some(ErrorKind::ArgumentConflict),
+                "{:?}",
+                command_line
+            );
+        }
+    }
+
+    #[test]
+    fn apply_rules_of_requires_on() {
+        assert_eq!(
+            error_kind(&["run", "--apply-rules-of", "INBOX"]),
+            Some(ErrorKind::MissingRequiredArgument)
+        );
+    }
+
+    #[test]
+    fn options_belong_to_their_command() {
+        let rejected: [&[&str]; 6] = [
+            &["--nomove"],
+            &["--nomove", "rules", "list"],
+            &["tags", "--interactive"],
+            &["lists", "--tag", "daily"],
+            &["--listrules", "tags"],
+            &["--listrules", "--listtags"],
+        ];
+        for command_line in rejected {
+            assert!(parse(command_line).is_err(), "{:?}", command_line);
+        }
+        let accepted: [&[&str]; 4] = [
+            &["-c", "other.ini", "rules", "list", "--tag", "daily"],
+            &["rules", "check", "--rules", "other.yaml"],
+            &["--listrules"],
+            &["dedupe", "--nomove", "-F", "Old"],
+        ];
+        for command_line in accepted {
+            assert!(parse(command_line).is_ok(), "{:?}", command_line);
         }
     }
-    // be nice to the server and log out
-    imap_session.logout().expect("failed to logout");
+
+    #[test]
+    fn former_list_flags_stand_for_their_command() {
+        assert!(matches!(parse(&["--listfolders"]), Ok(Command::Folders)));
+        assert!(matches!(
+            parse(&["--listrules"]).unwrap(),
+            Command::Rules {
+                command: RulesCommand::List { tag: None }
+            }
+        ));
+    }
 }
@@ -0,0 +1,482 @@
+use crate::sieve::quoted;
+use anyhow::{Context, Result};
+use base64::Engine;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+// ManageSieve client (rfc5804), to upload scripts generated from rules
+
+pub trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+#[derive(Debug, PartialEq)]
+enum Item {
+    Atom(String),
+    Str(String),
+}
+
+impl Item {
+    fn text(&self) -> &str {
+        match self {
+            Item::Atom(text) | Item::Str(text) => text,
+        }
+    }
+}
+
+// OK, NO or BYE line ending every server response
+#[derive(Debug)]
+struct Status {
+    ok: bool,
+    code: Option<String>,
+    text: String,
+}
+
+pub struct Client<S: Read + Write> {
+    stream: BufReader<S>,
+    // capability names (upper case) and their value, as announced by the server
+    pub capabilities: Vec<(String, String)>,
+}
+
+impl<S: Read + Write> Client<S> {
+    // read the capabilities the server greets with
+    pub fn new(stream: S) -> Result<Client<S>> {
+        let mut client = Client {
+            stream: BufReader::new(stream),
+            capabilities: Vec::new(),
+        };
+        client.read_capabilities("greeting")?;
+        Ok(client)
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|(capability, _)| capability.eq_ignore_ascii_case(name))
+    }
+
+    fn read_capabilities(&mut self, command: &str) -> Result<()> {
+        let lines = self.read_response(command)?;
+        self.capabilities = lines
+            .iter()
+            .filter_map(|line| match line.as_slice() {
+                [name] => Some((name.text().to_uppercase(), String::new())),
+                [name, value, ..] => Some((name.text().to_uppercase(), value.text().to_string())),
+                [] => None,
+            })
+            .collect();
+        Ok(())
+    }
+
+    // one line of the response, with the literals it announces read in
+    fn read_line(&mut self) -> Result<Vec<Item>> {
+        let mut items = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            self.stream.read_until(b'\n', &mut line)?;
+            if line.is_empty() {
+                anyhow::bail!("ManageSieve server closed the connection");
+            }
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            match parse_items(line, &mut items)? {
+                Some(length) => {
+                    let mut literal = vec![0; length];
+                    self.stream.read_exact(&mut literal)?;
+                    items.push(Item::Str(String::from_utf8_lossy(&literal).to_string()));
+                }
+                None => return Ok(items),
+            }
+        }
+    }
+
+    // data lines of the response, failing if the server does not answer OK
+    fn read_response(&mut self, command: &str) -> Result<Vec<Vec<Item>>> {
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            log::trace!("S: {:?}", line);
+            if let Some(status) = parse_status(&line) {
+                if !status.ok {
+                    match status.code {
+                        Some(code) => {
+                            anyhow::bail!("{} failed: {} ({})", command, status.text, code)
+                        }
+                        None => anyhow::bail!("{} failed: {}", command, status.text),
+                    }
+                }
+                return Ok(lines);
+            }
+            lines.push(line);
+        }
+    }
+
+    fn send(&mut self, command: &str) -> Result<()> {
+        log::trace!("C: {}", command);
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    fn command(&mut self, command: &str) -> Result<Vec<Vec<Item>>> {
+        self.send(command)?;
+        let name = command.split(' ').next().unwrap_or(command).to_string();
+        self.read_response(&name)
+    }
+
+    pub fn starttls(&mut self) -> Result<()> {
+        self.command("STARTTLS")?;
+        Ok(())
+    }
+
+    pub fn authenticate(&mut self, username: &str, password: &str) -> Result<()> {
+        let plain = format!("\0{}\0{}", username, password);
+        let encoded = base64::engine::general_purpose::STANDARD.encode(plain);
+        self.command(&format!("AUTHENTICATE \"PLAIN\" {}", quoted(&encoded)))?;
+        Ok(())
+    }
+
+    // names of the scripts on the server, and whether each is the active one
+    pub fn list_scripts(&mut self) -> Result<Vec<(String, bool)>> {
+        let lines = self.command("LISTSCRIPTS")?;
+        Ok(lines
+            .iter()
+            .filter_map(|line| match line.as_slice() {
+                [name] => Some((name.text().to_string(), false)),
+                [name, active, ..] => Some((
+                    name.text().to_string(),
+                    active.text().eq_ignore_ascii_case("ACTIVE"),
+                )),
+                [] => None,
+            })
+            .collect())
+    }
+
+    pub fn get_script(&mut self, name: &str) -> Result<String> {
+        let lines = self.command(&format!("GETSCRIPT {}", quoted(name)))?;
+        Ok(lines
+            .iter()
+            .flatten()
+            .map(|item| item.text())
+            .collect::<Vec<&str>>()
+            .concat())
+    }
+
+    pub fn check_script(&mut self, script: &str) -> Result<()> {
+        self.command(&format!("CHECKSCRIPT {}", literal(script)))?;
+        Ok(())
+    }
+
+    pub fn put_script(&mut self, name: &str, script: &str) -> Result<()> {
+        self.command(&format!("PUTSCRIPT {} {}", quoted(name), literal(script)))?;
+        Ok(())
+    }
+
+    pub fn set_active(&mut self, name: &str) -> Result<()> {
+        self.command(&format!("SETACTIVE {}", quoted(name)))?;
+        Ok(())
+    }
+
+    pub fn logout(&mut self) -> Result<()> {
+        self.command("LOGOUT")?;
+        Ok(())
+    }
+}
+
+// non synchronizing literal, scripts use CRLF line endings
+fn literal(script: &str) -> String {
+    let script = script.replace("\r\n", "\n").replace('\n', "\r\n");
+    format!("{{{}+}}\r\n{}", script.len(), script)
+}
+
+// append the items of a response line, returning the length of the literal that ends it if any
+fn parse_items(line: &str, items: &mut Vec<Item>) -> Result<Option<usize>> {
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => {}
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => value.extend(chars.next()),
+                        Some('"') => break,
+                        Some(c) => value.push(c),
+                        None => anyhow::bail!("unterminated string from ManageSieve server"),
+                    }
+                }
+                items.push(Item::Str(value));
+            }
+            '{' => {
+                let rest: String = chars.by_ref().collect();
+                let length = rest
+                    .trim_end_matches('}')
+                    .trim_end_matches('+')
+                    .parse()
+                    .with_context(|| {
+                        format!("invalid literal from ManageSieve server: {}", line)
+                    })?;
+                return Ok(Some(length));
+            }
+            '(' | ')' => items.push(Item::Atom(c.to_string())),
+            c => {
+                let mut atom = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next == ' ' || next == '(' || next == ')' {
+                        break;
+                    }
+                    atom.push(next);
+                    chars.next();
+                }
+                items.push(Item::Atom(atom));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn parse_status(line: &[Item]) -> Option<Status> {
+    let ok = match line.first() {
+        Some(Item::Atom(atom)) if atom.eq_ignore_ascii_case("OK") => true,
+        Some(Item::Atom(atom))
+            if atom.eq_ignore_ascii_case("NO") || atom.eq_ignore_ascii_case("BYE") =>
+        {
+            false
+        }
+        _ => return None,
+    };
+    let mut rest = &line[1..];
+    let mut code = None;
+    if rest.first() == Some(&Item::Atom("(".to_string())) {
+        let end = rest
+            .iter()
+            .position(|item| *item == Item::Atom(")".to_string()))
+            .unwrap_or(rest.len());
+        let words: Vec<&str> = rest[1..end].iter().map(|item| item.text()).collect();
+        code = Some(words.join(" "));
+        rest = &rest[(end + 1).min(rest.len())..];
+    }
+    let text = rest
+        .iter()
+        .map(|item| item.text())
+        .collect::<Vec<&str>>()
+        .join(" ");
+    Some(Status { ok, code, text })
+}
+
+// connect, switch to TLS when asked and log in
+pub fn connect(
+    host: &str,
+    port: u16,
+    tls: bool,
+    username: &str,
+    password: &str,
+) -> Result<Client<Box<dyn Stream>>> {
+    let stream = TcpStream::connect((host, port))
+        .with_context(|| format!("cannot connect to ManageSieve server {}:{}", host, port))?;
+    let mut client = Client::new(stream)?;
+    let client_capabilities = client.capabilities.clone();
+    let mut client: Client<Box<dyn Stream>> = if tls {
+        if !client.has_capability("STARTTLS") {
+            anyhow::bail!("ManageSieve server does not offer STARTTLS");
+        }
+        client.starttls()?;
+        let connector = rustls_connector::RustlsConnector::new_with_native_certs()?;
+        let stream = connector
+            .connect(host, client.into_inner())
+            .map_err(|error| anyhow::anyhow!("TLS with ManageSieve server failed: {}", error))?;
+        // the server announces its capabilities again once in TLS
+        Client::new(Box::new(stream) as Box<dyn Stream>)?
+    } else {
+        Client {
+            stream: BufReader::new(Box::new(client.into_inner())),
+            capabilities: client_capabilities,
+        }
+    };
+    client.authenticate(username, password)?;
+    Ok(client)
+}
+
+// line by line differences between two scripts, - for removed and + for added lines
+pub fn diff_lines(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // longest common subsequence, from the end
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push_str(&format!("  {}\n", old[i]));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] > common[i + 1][j]) {
+            diff.push_str(&format!("+ {}\n", new[j]));
+            j += 1;
+        } else {
+            diff.push_str(&format!("- {}\n", old[i]));
+            i += 1;
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // server side of a session written in advance, what the client sends is kept to be checked
+    struct Scripted {
+        server: Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            self.server.read(buffer)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+            self.sent.extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const GREETING: &str = "\"IMPLEMENTATION\" \"Test\"\r\n\"SIEVE\" \"fileinto imap4flags\"\r\n\"STARTTLS\"\r\nOK \"ready\"\r\n";
+
+    fn client(responses: &str) -> Client<Scripted> {
+        Client::new(Scripted {
+            server: Cursor::new(format!("{}{}", GREETING, responses).into_bytes()),
+            sent: Vec::new(),
+        })
+        .unwrap()
+    }
+
+    fn sent(client: Client<Scripted>) -> String {
+        String::from_utf8(client.into_inner().sent).unwrap()
+    }
+
+    #[test]
+    fn greeting_capabilities() {
+        let client = client("");
+        assert!(client.has_capability("starttls"));
+        assert!(!client.has_capability("UNAUTHENTICATE"));
+        assert_eq!(
+            client.capabilities[1],
+            ("SIEVE".to_string(), "fileinto imap4flags".to_string())
+        );
+    }
+
+    #[test]
+    fn authenticate_plain() {
+        let mut client = client("OK\r\n");
+        client.authenticate("user", "secret").unwrap();
+        // base64 of \0user\0secret
+        assert_eq!(
+            sent(client),
+            "AUTHENTICATE \"PLAIN\" \"AHVzZXIAc2VjcmV0\"\r\n"
+        );
+    }
+
+    #[test]
+    fn literals_in_responses() {
+        let script = "keep;\r\nstop;\r\n";
+        let mut client = client(&format!(
+            "{{{}}}\r\n{}\r\nOK\r\n{{{}+}}\r\n{}\r\nOK\r\n",
+            script.len(),
+            script,
+            script.len(),
+            script
+        ));
+        assert_eq!(client.get_script("one").unwrap(), script);
+        assert_eq!(client.get_script("two").unwrap(), script);
+        assert_eq!(sent(client), "GETSCRIPT \"one\"\r\nGETSCRIPT \"two\"\r\n");
+    }
+
+    #[test]
+    fn list_scripts_marks_the_active_one() {
+        let mut client = client("\"vacation\"\r\n\"imap_sorter\" ACTIVE\r\nOK\r\n");
+        assert_eq!(
+            client.list_scripts().unwrap(),
+            vec![
+                ("vacation".to_string(), false),
+                ("imap_sorter".to_string(), true)
+            ]
+        );
+    }
+
+    #[test]
+    fn put_script_then_set_active() {
+        let mut client = client("OK\r\nOK\r\n");
+        client.put_script("imap_sorter", "keep;\nstop;\n").unwrap();
+        client.set_active("imap_sorter").unwrap();
+        assert_eq!(
+            sent(client),
+            "PUTSCRIPT \"imap_sorter\" {14+}\r\nkeep;\r\nstop;\r\n\r\nSETACTIVE \"imap_sorter\"\r\n"
+        );
+    }
+
+    #[test]
+    fn no_fails_with_its_code_and_text() {
+        let mut client = client("NO (QUOTA/MAXSIZE) \"script too big\"\r\n");
+        let error = client.put_script("big", "keep;").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "PUTSCRIPT failed: script too big (QUOTA/MAXSIZE)"
+        );
+    }
+
+    #[test]
+    fn no_with_a_literal_text() {
+        let text = "line 1: error";
+        let mut client = client(&format!("NO {{{}}}\r\n{}\r\n", text.len(), text));
+        let error = client.check_script("bad").unwrap_err();
+        assert_eq!(error.to_string(), "CHECKSCRIPT failed: line 1: error");
+    }
+
+    #[test]
+    fn bye_and_closed_connection_fail() {
+        let mut client = client("BYE \"going away\"\r\n");
+        assert_eq!(
+            client.set_active("x").unwrap_err().to_string(),
+            "SETACTIVE failed: going away"
+        );
+        let mut client = self::client("");
+        assert_eq!(
+            client.logout().unwrap_err().to_string(),
+            "ManageSieve server closed the connection"
+        );
+    }
+
+    #[test]
+    fn diff_of_scripts() {
+        let old = "require \"fileinto\";\nif a { fileinto \"A\"; }\nif b { fileinto \"B\"; }\n";
+        let new = "require \"fileinto\";\nif b { fileinto \"B\"; }\nif c { fileinto \"C\"; }\n";
+        assert_eq!(
+            diff_lines(old, new),
+            "  require \"fileinto\";\n- if a { fileinto \"A\"; }\n  if b { fileinto \"B\"; }\n+ if c { fileinto \"C\"; }\n"
+        );
+        assert_eq!(diff_lines("same\n", "same\n"), "  same\n");
+    }
+}
@@ -0,0 +1,172 @@
+use crate::account::Session;
+use crate::sorter::RunReport;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// metrics of the runs, in the Prometheus text format, for the node_exporter textfile collector
+// or scraped from /metrics when running as a daemon
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // messages moved since the process started, by rule and target
+    moved: BTreeMap<(String, String), u64>,
+    // rules failing since the process started, by rule
+    rule_errors: BTreeMap<String, u64>,
+    // runs since the process started, by result
+    runs: BTreeMap<&'static str, u64>,
+    last_run_duration: f64,
+    last_success: Option<i64>,
+    // messages in each folder after the last run
+    folder_sizes: BTreeMap<String, u32>,
+}
+
+// label values are quoted, with backslashes, quotes and new lines escaped
+fn label(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+impl Metrics {
+    // count what a run did, it succeeded if no rule failed
+    pub fn record_run(&mut self, report: &RunReport) {
+        for folder in &report.folders {
+            for rule in &folder.rules {
+                if rule.moved > 0 {
+                    *self
+                        .moved
+                        .entry((rule.rule.clone(), rule.target.clone()))
+                        .or_default() += rule.moved as u64;
+                }
+                if !rule.errors.is_empty() {
+                    *self.rule_errors.entry(rule.rule.clone()).or_default() += 1;
+                }
+            }
+        }
+        self.last_run_duration = report.duration_ms as f64 / 1000.0;
+        if report.errors() == 0 {
+            self.last_success = Some(chrono::Utc::now().timestamp());
+            *self.runs.entry("success").or_default() += 1;
+        } else {
+            *self.runs.entry("error").or_default() += 1;
+        }
+    }
+
+    // a run that could not even connect
+    pub fn record_failure(&mut self) {
+        *self.runs.entry("error").or_default() += 1;
+    }
+
+    pub fn set_folder_size(&mut self, folder: &str, messages: u32) {
+        self.folder_sizes.insert(folder.to_string(), messages);
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        text.push_str(
+            "# HELP imap_sorter_messages_moved_total Messages moved by rule and target.\n",
+        );
+        text.push_str("# TYPE imap_sorter_messages_moved_total counter\n");
+        for ((rule, target), count) in &self.moved {
+            text.push_str(&format!(
+                "imap_sorter_messages_moved_total{{rule={},target={}}} {}\n",
+                label(rule),
+                label(target),
+                count
+            ));
+        }
+        text.push_str("# HELP imap_sorter_rule_errors_total Rules that failed, by rule.\n");
+        text.push_str("# TYPE imap_sorter_rule_errors_total counter\n");
+        for (rule, count) in &self.rule_errors {
+            text.push_str(&format!(
+                "imap_sorter_rule_errors_total{{rule={}}} {}\n",
+                label(rule),
+                count
+            ));
+        }
+        text.push_str("# HELP imap_sorter_runs_total Runs by result.\n");
+        text.push_str("# TYPE imap_sorter_runs_total counter\n");
+        for (result, count) in &self.runs {
+            text.push_str(&format!(
+                "imap_sorter_runs_total{{result={}}} {}\n",
+                label(result),
+                count
+            ));
+        }
+        text.push_str("# HELP imap_sorter_run_duration_seconds Duration of the last run.\n");
+        text.push_str("# TYPE imap_sorter_run_duration_seconds gauge\n");
+        text.push_str(&format!(
+            "imap_sorter_run_duration_seconds {}\n",
+            self.last_run_duration
+        ));
+        if let Some(timestamp) = self.last_success {
+            text.push_str("# HELP imap_sorter_last_success_timestamp_seconds End of the last run without errors.\n");
+            text.push_str("# TYPE imap_sorter_last_success_timestamp_seconds gauge\n");
+            text.push_str(&format!(
+                "imap_sorter_last_success_timestamp_seconds {}\n",
+                timestamp
+            ));
+        }
+        text.push_str(
+            "# HELP imap_sorter_folder_messages Messages in the folder after the last run.\n",
+        );
+        text.push_str("# TYPE imap_sorter_folder_messages gauge\n");
+        for (folder, messages) in &self.folder_sizes {
+            text.push_str(&format!(
+                "imap_sorter_folder_messages{{folder={}}} {}\n",
+                label(folder),
+                messages
+            ));
+        }
+        text
+    }
+
+    // written aside then renamed, so that node_exporter never reads half a file
+    pub fn write_textfile(&self, path: &str) -> anyhow::Result<()> {
+        let temporary = format!("{}.tmp", path);
+        std::fs::write(&temporary, self.render())
+            .with_context(|| format!("cannot write metrics to {}", temporary))?;
+        std::fs::rename(&temporary, path)
+            .with_context(|| format!("cannot write metrics to {}", path))?;
+        Ok(())
+    }
+}
+
+// record the number of messages of folders, those that cannot be examined are left out
+pub fn record_folder_sizes(metrics: &mut Metrics, imap_session: &mut Session, folders: &[String]) {
+    for folder in folders {
+        match imap_session.examine(folder) {
+            Ok(mailbox) => metrics.set_folder_size(folder, mailbox.exists),
+            Err(error) => log::debug!("cannot examine {}: {}", folder, error),
+        }
+    }
+}
+
+// serve the metrics on http://ADDRESS/metrics, from a thread of its own
+pub fn serve(address: &str, metrics: Arc<Mutex<Metrics>>) -> anyhow::Result<()> {
+    let server = tiny_http::Server::http(address)
+        .map_err(|error| anyhow::anyhow!("cannot listen on {}: {}", address, error))?;
+    log::info!("serving metrics on http://{}/metrics", address);
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = if request.url() == "/metrics" {
+                let text = metrics.lock().expect("metrics lock").render();
+                let content_type = tiny_http::Header::from_bytes(
+                    "Content-Type",
+                    "text/plain; version=0.0.4; charset=utf-8",
+                )
+                .expect("valid header");
+                tiny_http::Response::from_string(text).with_header(content_type)
+            } else {
+                tiny_http::Response::from_string("not found").with_status_code(404)
+            };
+            if let Err(error) = request.respond(response) {
+                log::debug!("cannot answer metrics request: {}", error);
+            }
+        }
+    });
+    Ok(())
+}
@@ -1,34 +1,89 @@
+use crate::classifier::{ClassifierConfig, DEFAULT_MIN_PROBABILITY};
+use crate::schedule::Schedule;
+use crate::tags::TagExpression;
 use anyhow::{Context, Result};
+use chrono::{Days, NaiveDate};
 use serde::{Deserialize, Serialize};
-use serde_yaml;
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::BufReader;
-#[derive(Debug, Deserialize, Serialize, Clone)]
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Rule {
     pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub filter: String,
+    // match messages of this mailing list, by its List-Id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub list: Option<String>,
+    // match messages the classifier puts in this class
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub classifier: Option<String>,
+    // probability of the class below which the classifier leaves a message alone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_probability: Option<f64>,
+    // target folder, may contain {list_id} of the message
     pub target: String,
+    // flags set on messages before they are moved, like \Seen or a keyword
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags: Option<Vec<String>>,
     pub enable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub tags: Option<Vec<String>>,
+    // also move the other messages of the conversation
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub thread: bool,
+    // run only when due, otherwise at each run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+    // file this rule was loaded from, filled in by RulesSet::load
+    #[serde(skip)]
+    pub source: Option<String>,
 }
 
 impl Rule {
-    pub fn match_tag(&self, tag: &Option<String>) -> bool {
-        let tag = match tag {
-            Some(tag) => tag.clone(),
-            None => return true, // if no tag requested, then it matches
-        };
+    pub fn match_tag(&self, tag: &Option<TagExpression>) -> bool {
+        match tag {
+            // rules without tags only match expressions like !slow
+            Some(tag) => tag.matches(self.tags.as_deref().unwrap_or_default()),
+            None => true, // if no tag requested, then it matches
+        }
+    }
 
-        // from here we know we need to match a tag
-        let rule_tags = match &self.tags {
-            Some(tags) => tags,
-            _ => return false, // if rules has no tags, then cannot match the request tag
-        };
+    // IMAP search for messages matching this rule, the filter and the list condition
+    pub fn search_filter(&self) -> String {
+        let mut criteria: Vec<String> = Vec::new();
+        if !self.filter.trim().is_empty() {
+            criteria.push(self.filter.clone());
+        }
+        if let Some(list) = &self.list {
+            criteria.push(format!("HEADER List-Id \"{}\"", list.replace('"', "")));
+        }
+        // the classifier alone looks at every message
+        if criteria.is_empty() && self.classifier.is_some() {
+            criteria.push("ALL".to_string());
+        }
+        criteria.join(" ")
+    }
+
+    pub fn min_probability(&self) -> f64 {
+        self.min_probability.unwrap_or(DEFAULT_MIN_PROBABILITY)
+    }
+
+    pub fn target_uses_list_id(&self) -> bool {
+        self.target.contains("{list_id}")
+    }
+
+    // account name and folder of an account:name/folder target, on another IMAP account
+    pub fn target_account(&self) -> Option<(&str, &str)> {
+        self.target.strip_prefix("account:")?.split_once('/')
+    }
 
-        // check if tag if in the tag list
-        rule_tags.contains(&tag)
+    pub fn target_for_list(&self, list_id: &str) -> String {
+        self.target.replace("{list_id}", list_id)
     }
+
     pub fn tags_string(&self) -> String {
         match &self.tags {
             Some(tags) => tags.join(", "),
@@ -37,13 +92,44 @@ impl Rule {
     }
 
     pub fn as_string(&self) -> String {
+        let thread = if self.thread { "\n\tthread: true" } else { "" };
+        let list = match &self.list {
+            Some(list) => format!("\n\tlist: {}", list),
+            None => "".to_string(),
+        };
+        let classifier = match &self.classifier {
+            Some(class) => format!(
+                "\n\tclassifier: {} (min probability {})",
+                class,
+                self.min_probability()
+            ),
+            None => "".to_string(),
+        };
+        let flags = match &self.flags {
+            Some(flags) => format!("\n\tflags: {}", flags.join(" ")),
+            None => "".to_string(),
+        };
+        let schedule = match &self.schedule {
+            Some(schedule) => format!("\n\tschedule: {}", schedule.as_string()),
+            None => "".to_string(),
+        };
+        let source = match &self.source {
+            Some(source) => format!("\n\tsource: {}", source),
+            None => "".to_string(),
+        };
         format!(
             // "{:<25} filter: {:<60} target: {:<15} tags: {:<20}",
-            "* rule:\t{}\n\tfilter: {}\n\ttarget: {}\n\ttags: {}",
+            "* rule:\t{}\n\tfilter: {}{}{}\n\ttarget: {}{}\n\ttags: {}{}{}{}",
             &self.name,
             &self.filter,
+            list,
+            classifier,
             &self.target,
-            &self.tags_string()
+            flags,
+            &self.tags_string(),
+            thread,
+            schedule,
+            source
         )
     }
 
@@ -60,19 +146,215 @@ impl Rule {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RetentionAction {
+    Delete,
+    Archive,
+}
+
+// remove or archive messages of a folder once they are older than a number of days
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RetentionRule {
+    pub name: String,
+    pub older_than_days: u32,
+    pub action: RetentionAction,
+    // target folder when archiving, may contain {year} and {month} of the message date
+    pub target: Option<String>,
+    // additional IMAP search criteria, to narrow down the messages
+    pub filter: Option<String>,
+    pub enable: bool,
+    // flagged messages are kept forever, unless told otherwise
+    #[serde(default = "default_keep_flagged")]
+    pub keep_flagged: bool,
+}
+
+fn default_keep_flagged() -> bool {
+    true
+}
+
+impl RetentionRule {
+    // IMAP search for messages older than the retention period, as of `today`
+    pub fn search_filter(&self, today: NaiveDate) -> String {
+        let limit = today - Days::new(self.older_than_days.into());
+        let mut filter = format!("BEFORE {}", limit.format("%d-%b-%Y"));
+        if self.keep_flagged {
+            filter.push_str(" UNFLAGGED");
+        }
+        if let Some(extra) = &self.filter {
+            filter.push(' ');
+            filter.push_str(extra);
+        }
+        filter
+    }
+
+    // archive folder of a message, according to its date
+    pub fn target_for(&self, date: &NaiveDate) -> Option<String> {
+        self.target.as_ref().map(|target| {
+            target
+                .replace("{year}", &date.format("%Y").to_string())
+                .replace("{month}", &date.format("%m").to_string())
+        })
+    }
+
+    pub fn as_string(&self) -> String {
+        let action = match self.action {
+            RetentionAction::Delete => "delete".to_string(),
+            RetentionAction::Archive => {
+                format!("archive to {}", self.target.as_deref().unwrap_or("?"))
+            }
+        };
+        format!(
+            "* retention:\t{}\n\tolder than: {} days\n\taction: {}\n\tkeep flagged: {}",
+            &self.name, self.older_than_days, action, self.keep_flagged
+        )
+    }
+}
+
+// move away duplicated messages of a folder, optionally looking for them in other folders too
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DedupeRule {
+    pub target: String,
+    // other folders searched along with this one, the oldest copy is kept wherever it is
+    pub folders: Option<Vec<String>>,
+    pub enable: bool,
+}
+
+impl DedupeRule {
+    pub fn as_string(&self) -> String {
+        let folders = match &self.folders {
+            Some(folders) => folders.join(", "),
+            None => "".to_string(),
+        };
+        format!(
+            "* dedupe:\ttarget: {}\n\talso in: {}",
+            &self.target, folders
+        )
+    }
+}
+
+// a named set of rules that can be referenced by several FolderRule
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RuleGroup {
+    pub name: String,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
 pub struct FolderRule {
     pub folder: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub folders: Option<Vec<String>>,
+    #[serde(default)]
     pub rules: Vec<Rule>,
+    // names of shared groups whose rules also apply to this folder
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<String>>,
+    // folders (or IMAP LIST patterns) to leave out of folder/folders patterns
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Vec<RetentionRule>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dedupe: Option<DedupeRule>,
+    // schedule of the rules of this folder that have none of their own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+    // actual folders matched on the server, filled in by RulesSet::resolve_folders
+    #[serde(skip)]
+    pub resolved: Option<Vec<String>>,
+}
+
+// tell if a folder name is an IMAP LIST pattern rather than a plain name
+pub fn is_folder_pattern(name: &str) -> bool {
+    name.contains(['*', '%'])
+}
+
+// match a folder name against an IMAP LIST pattern (rfc3501 section 6.3.8)
+// `*` matches anything, `%` matches anything but the hierarchy delimiter
+pub fn folder_matches(pattern: &str, name: &str, delimiter: &str) -> bool {
+    fn matches(pattern: &[char], name: &[char], delimiter: &[char]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some(('*', rest)) => (0..=name.len()).any(|i| matches(rest, &name[i..], delimiter)),
+            Some(('%', rest)) => {
+                for i in 0..=name.len() {
+                    if matches(rest, &name[i..], delimiter) {
+                        return true;
+                    }
+                    if i < name.len() && name[i..].starts_with(delimiter) {
+                        return false;
+                    }
+                }
+                false
+            }
+            Some((c, rest)) => name.first() == Some(c) && matches(rest, &name[1..], delimiter),
+        }
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let delimiter: Vec<char> = delimiter.chars().collect();
+    matches(&pattern, &name, &delimiter)
 }
 
 impl FolderRule {
+    // folder names or patterns this entry applies to, as written in the configuration
+    pub fn folder_specs(&self) -> Vec<String> {
+        let mut folders = vec![self.folder.clone()];
+        if let Some(folder_list) = &self.folders {
+            folders.extend(folder_list.clone());
+        }
+        folders
+    }
+
+    // folders this entry applies to, once resolved against the server if it has been
+    pub fn source_folders(&self) -> Vec<String> {
+        match &self.resolved {
+            Some(resolved) => resolved.clone(),
+            None => self.folder_specs(),
+        }
+    }
+
+    pub fn has_patterns(&self) -> bool {
+        self.folder_specs()
+            .iter()
+            .any(|spec| is_folder_pattern(spec))
+            || self
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| !exclude.is_empty())
+    }
+
+    // match patterns and exclusions against the list of folders available on the server
+    pub fn resolve_folders(&mut self, mailboxes: &[String], delimiter: &str) {
+        let mut resolved: Vec<String> = Vec::new();
+        for spec in self.folder_specs() {
+            if is_folder_pattern(&spec) {
+                resolved.extend(
+                    mailboxes
+                        .iter()
+                        .filter(|mailbox| folder_matches(&spec, mailbox, delimiter))
+                        .cloned(),
+                );
+            } else {
+                resolved.push(spec);
+            }
+        }
+        if let Some(exclude) = &self.exclude {
+            resolved.retain(|folder| {
+                !exclude
+                    .iter()
+                    .any(|pattern| folder_matches(pattern, folder, delimiter))
+            });
+        }
+        self.resolved = Some(resolved);
+    }
+
     pub fn list_tags(&self) -> Vec<String> {
         let mut all_tags: Vec<String> = Vec::new();
         for rule in &self.rules {
             if let Some(tag) = &rule.tags {
-                all_tags.extend_from_slice(&tag)
+                all_tags.extend_from_slice(tag)
             }
         }
         all_tags.sort();
@@ -80,27 +362,364 @@ impl FolderRule {
         all_tags
     }
 
-    pub fn print(&self) {
+    // print the folder, with only the rules selected by a tag expression when there is one
+    pub fn print(&self, tag: &Option<TagExpression>) {
         println!("Folder: {}", &self.folder);
-        for rule in &self.rules {
+        if let Some(exclude) = &self.exclude {
+            println!("Exclude: {}", exclude.join(", "));
+        }
+        if let Some(schedule) = &self.schedule {
+            println!("Schedule: {}", schedule.as_string());
+        }
+        for rule in self.rules.iter().filter(|rule| rule.match_tag(tag)) {
             rule.print();
         }
+        if let Some(groups) = &self.groups {
+            println!("Groups: {}", groups.join(", "));
+        }
+        for retention in self.retention.iter().flatten() {
+            println!("{}", retention.as_string());
+        }
+        if let Some(dedupe) = &self.dedupe {
+            println!("{}", dedupe.as_string());
+        }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Default)]
 pub struct RulesSet {
+    // other rule files (or glob patterns) to load, relative to this file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<RuleGroup>>,
+    // model and training folders for rules with a classifier condition
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub classifier: Option<ClassifierConfig>,
+    #[serde(default)]
     pub folders: Vec<FolderRule>,
 }
 
 impl RulesSet {
     pub fn load(file_name: &str) -> Result<Self> {
+        let mut seen = HashSet::new();
+        let rules_set = Self::load_file(Path::new(file_name), &mut Vec::new(), &mut seen)?;
+        rules_set.check_groups()?;
+        rules_set.check_rules()?;
+        rules_set.check_retention()?;
+        Ok(rules_set)
+    }
+
+    // load one rule file and everything it includes
+    // `stack` is the chain of files currently being loaded, used to detect include cycles
+    // `seen` is every file already loaded, so a file included twice is only merged once
+    fn load_file(
+        file_name: &Path,
+        stack: &mut Vec<PathBuf>,
+        seen: &mut HashSet<PathBuf>,
+    ) -> Result<Self> {
+        let canonical = file_name
+            .canonicalize()
+            .with_context(|| format!("Failed to open file: {}", file_name.display()))?;
+        if stack.contains(&canonical) {
+            let chain: Vec<String> = stack
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|path| path.display().to_string())
+                .collect();
+            anyhow::bail!("include cycle detected: {}", chain.join(" -> "));
+        }
+        seen.insert(canonical.clone());
+
+        let file = File::open(file_name)
+            .with_context(|| format!("Failed to open file: {}", file_name.display()))?;
+        let reader = BufReader::new(file);
+        let mut rules_set: RulesSet = serde_yaml::from_reader(reader)
+            .with_context(|| format!("Failed to parse YAML file: {}", file_name.display()))?;
+
+        // remember where each rule comes from
+        let source = file_name.display().to_string();
+        for folder in rules_set.folders.iter_mut() {
+            for rule in folder.rules.iter_mut() {
+                rule.source = Some(source.clone());
+            }
+        }
+        for group in rules_set.groups.iter_mut().flatten() {
+            for rule in group.rules.iter_mut() {
+                rule.source = Some(source.clone());
+            }
+        }
+
+        let includes = match &rules_set.include {
+            Some(includes) => includes.clone(),
+            None => return Ok(rules_set),
+        };
+
+        // included rules come first, so that personal rules are applied on top of shared ones
+        stack.push(canonical);
+        let base_dir = file_name.parent().unwrap_or(Path::new(""));
+        let mut folders = Vec::new();
+        let mut groups = Vec::new();
+        let mut classifier = None;
+        for include in includes {
+            for path in Self::expand_include(base_dir, &include)? {
+                let canonical = path.canonicalize().ok();
+                if canonical
+                    .as_ref()
+                    .is_some_and(|c| seen.contains(c) && !stack.contains(c))
+                {
+                    log::debug!("already included, skipping: {}", path.display());
+                    continue;
+                }
+                let included = Self::load_file(&path, stack, seen)?;
+                folders.extend(included.folders);
+                groups.extend(included.groups.unwrap_or_default());
+                classifier = classifier.or(included.classifier);
+            }
+        }
+        stack.pop();
+
+        folders.append(&mut rules_set.folders);
+        rules_set.folders = folders;
+        groups.extend(rules_set.groups.take().unwrap_or_default());
+        rules_set.groups = Some(groups);
+        // the classifier of the including file wins
+        rules_set.classifier = rules_set.classifier.take().or(classifier);
+        Ok(rules_set)
+    }
+
+    // turn an include entry into the list of files it designates, glob patterns are expanded
+    fn expand_include(base_dir: &Path, include: &str) -> Result<Vec<PathBuf>> {
+        let path = base_dir.join(include);
+        if !include.contains(['*', '?', '[']) {
+            return Ok(vec![path]);
+        }
+        let pattern = path.to_string_lossy();
+        let mut paths = glob::glob(&pattern)
+            .with_context(|| format!("Invalid include pattern: {}", include))?
+            .collect::<std::result::Result<Vec<PathBuf>, _>>()
+            .with_context(|| format!("Failed to expand include pattern: {}", include))?;
+        if paths.is_empty() {
+            log::warn!("include pattern matches no file: {}", include);
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    // make sure every group referenced by a folder exists, and is defined only once
+    fn check_groups(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for group in self.groups.iter().flatten() {
+            if !names.insert(group.name.as_str()) {
+                anyhow::bail!("rule group defined more than once: {}", group.name);
+            }
+        }
+        for folder in &self.folders {
+            for group in folder.groups.iter().flatten() {
+                if !names.contains(group.as_str()) {
+                    anyhow::bail!(
+                        "folder {} references unknown rule group: {}",
+                        folder.folder,
+                        group
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // a rule without any condition would match every message
+    fn check_rules(&self) -> Result<()> {
+        let rules = self
+            .folders
+            .iter()
+            .flat_map(|folder| &folder.rules)
+            .chain(self.groups.iter().flatten().flat_map(|group| &group.rules));
+        for rule in rules {
+            if rule.search_filter().is_empty() {
+                anyhow::bail!("rule {} has neither filter, list nor classifier", rule.name);
+            }
+            if let Some(class) = &rule.classifier {
+                let Some(classifier) = &self.classifier else {
+                    anyhow::bail!(
+                        "rule {} uses a classifier, but no classifier is configured",
+                        rule.name
+                    );
+                };
+                if !classifier.classes.contains_key(class) {
+                    anyhow::bail!(
+                        "rule {} uses class {}, which the classifier does not have",
+                        rule.name,
+                        class
+                    );
+                }
+            }
+            if let Some(probability) = rule.min_probability {
+                if !(0.0..=1.0).contains(&probability) {
+                    anyhow::bail!(
+                        "rule {} has a min_probability of {}, not between 0 and 1",
+                        rule.name,
+                        probability
+                    );
+                }
+            }
+            if let Some(schedule) = &rule.schedule {
+                schedule
+                    .check()
+                    .with_context(|| format!("rule {} has an invalid schedule", rule.name))?;
+            }
+        }
+        // a message is always put in one of the classes, so one class would take them all
+        if let Some(classifier) = &self.classifier {
+            if classifier.classes.len() < 2 {
+                anyhow::bail!(
+                    "the classifier needs at least two classes, like the one to sort and a catch-all"
+                );
+            }
+        }
+        for folder in &self.folders {
+            if let Some(schedule) = &folder.schedule {
+                schedule
+                    .check()
+                    .with_context(|| format!("folder {} has an invalid schedule", folder.folder))?;
+            }
+        }
+        Ok(())
+    }
+
+    // archiving needs somewhere to archive to
+    fn check_retention(&self) -> Result<()> {
+        for folder in &self.folders {
+            for retention in folder.retention.iter().flatten() {
+                if retention.action == RetentionAction::Archive && retention.target.is_none() {
+                    anyhow::bail!(
+                        "retention {} of folder {} archives without a target",
+                        retention.name,
+                        folder.folder
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // likely mistakes in rules that load fine, as (rule, problem), given the other accounts configured
+    pub fn lint(&self, accounts: &[String]) -> Vec<(String, String)> {
+        let mut problems = Vec::new();
+        // rules of groups are defined once, whatever the folders using them
+        let mut names = HashSet::new();
+        let defined = self
+            .folders
+            .iter()
+            .flat_map(|folder| &folder.rules)
+            .chain(self.groups.iter().flatten().flat_map(|group| &group.rules));
+        for rule in defined {
+            if !names.insert(rule.name.as_str()) {
+                problems.push((
+                    rule.name.clone(),
+                    "another rule has the same name".to_string(),
+                ));
+            }
+        }
+        for folder in &self.folders {
+            for rule in self.folder_rules(folder) {
+                let mut problem = |text: String| problems.push((rule.name.clone(), text));
+                if rule.target.trim().is_empty() {
+                    problem("no target".to_string());
+                }
+                if folder.source_folders().contains(&rule.target) {
+                    problem(format!("target {} is the folder it sorts", rule.target));
+                }
+                if rule.filter.matches('"').count() % 2 != 0 {
+                    problem("unbalanced quotes in filter".to_string());
+                }
+                let mut depth = 0i32;
+                for c in rule.filter.chars() {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                    if depth < 0 {
+                        break;
+                    }
+                }
+                if depth != 0 {
+                    problem("unbalanced parentheses in filter".to_string());
+                }
+                if let (Some(class), Some(classifier)) = (&rule.classifier, &self.classifier) {
+                    if !classifier.classes.contains_key(class) {
+                        problem(format!("class {} is not trained by the classifier", class));
+                    }
+                }
+                if rule.target_uses_list_id() && rule.list.is_none() {
+                    problem("target uses {list_id} but the rule has no list".to_string());
+                }
+                if rule.target.starts_with("account:") {
+                    match rule.target_account() {
+                        Some((account, _)) if !accounts.iter().any(|name| name == account) => {
+                            problem(format!("account {} is not configured", account))
+                        }
+                        Some(_) => {}
+                        None => problem("target account has no folder".to_string()),
+                    }
+                }
+            }
+        }
+        problems
+    }
+
+    // rules of a folder, including the ones coming from the groups it references
+    pub fn folder_rules(&self, folder: &FolderRule) -> Vec<Rule> {
+        let mut rules = folder.rules.clone();
+        for group_name in folder.groups.iter().flatten() {
+            if let Some(group) = self
+                .groups
+                .iter()
+                .flatten()
+                .find(|group| &group.name == group_name)
+            {
+                rules.extend_from_slice(&group.rules);
+            }
+        }
+        if let Some(schedule) = &folder.schedule {
+            for rule in rules.iter_mut().filter(|rule| rule.schedule.is_none()) {
+                rule.schedule = Some(schedule.clone());
+            }
+        }
+        rules
+    }
+
+    // add a rule to a folder of a rules file, the file alone is read and written back,
+    // its includes are left as they are
+    pub fn add_rule_to_file(file_name: &str, folder: &str, rule: Rule) -> Result<()> {
         let file =
             File::open(file_name).with_context(|| format!("Failed to open file: {}", file_name))?;
-        let reader = BufReader::new(file);
-        let rules_set: RulesSet = serde_yaml::from_reader(reader)
+        let mut rules_set: RulesSet = serde_yaml::from_reader(BufReader::new(file))
             .with_context(|| format!("Failed to parse YAML file: {}", file_name))?;
-        Ok(rules_set)
+        match rules_set
+            .folders
+            .iter_mut()
+            .find(|entry| entry.folder == folder && entry.folders.is_none())
+        {
+            Some(entry) => entry.rules.push(rule),
+            None => rules_set.folders.push(FolderRule {
+                folder: folder.to_string(),
+                rules: vec![rule],
+                ..Default::default()
+            }),
+        }
+        std::fs::write(file_name, serde_yaml::to_string(&rules_set)?)
+            .with_context(|| format!("Failed to write file: {}", file_name))?;
+        Ok(())
+    }
+
+    // first rule with this name, whatever folder it belongs to
+    pub fn find_rule(&self, name: &str) -> Option<Rule> {
+        self.folders
+            .iter()
+            .flat_map(|folder| self.folder_rules(folder))
+            .find(|rule| rule.name == name)
     }
 
     pub fn list_tags(&self) -> Vec<String> {
@@ -108,24 +727,54 @@ impl RulesSet {
             .folders
             .iter()
             .flat_map(|fld| fld.list_tags())
+            .chain(
+                self.groups
+                    .iter()
+                    .flatten()
+                    .flat_map(|group| &group.rules)
+                    .flat_map(|rule| rule.tags.clone().unwrap_or_default()),
+            )
             .collect();
         all_tags.sort();
         all_tags.dedup();
         all_tags
     }
 
+    pub fn uses_classifier(&self) -> bool {
+        self.folders
+            .iter()
+            .flat_map(|folder| self.folder_rules(folder))
+            .any(|rule| rule.classifier.is_some())
+    }
+
+    // names of the rules with a schedule, their own or the one of their folder
+    pub fn scheduled_rules(&self) -> HashSet<String> {
+        self.folders
+            .iter()
+            .flat_map(|folder| self.folder_rules(folder))
+            .filter(|rule| rule.schedule.is_some())
+            .map(|rule| rule.name)
+            .collect()
+    }
+
+    pub fn has_folder_patterns(&self) -> bool {
+        self.folders.iter().any(|folder| folder.has_patterns())
+    }
+
+    // resolve folder patterns of all entries against the folders available on the server
+    pub fn resolve_folders(&mut self, mailboxes: &[String], delimiter: &str) {
+        for folder in self.folders.iter_mut() {
+            folder.resolve_folders(mailboxes, delimiter);
+        }
+    }
+
     pub fn list_folders(&self) -> Vec<String> {
         // extract all folders from config, either in name or in folders parameter
+        // (or the matching folders once patterns have been resolved)
         let all_folders: Vec<String> = self
             .folders
             .iter()
-            .flat_map(|folder| {
-                let mut folders = vec![folder.folder.clone()];
-                if let Some(folder_list) = &folder.folders {
-                    folders.extend(folder_list.clone());
-                }
-                folders
-            })
+            .flat_map(|folder| folder.source_folders())
             .collect();
 
         // reduce list to unique folder, while preserving order of folder as defined in configuration file
@@ -149,26 +798,197 @@ impl RulesSet {
                 // for each folder, that match, collect rules
                 let mut rules: Vec<Rule> = Vec::new();
 
-                // check if this is the folder name
-                if fld.folder == folder {
-                    rules.extend_from_slice(&fld.rules);
+                // check if this is the folder name, or one of the folders (with a s) parameter
+                // or one of the folders matched by their patterns
+                if fld.source_folders().contains(&folder) {
+                    rules.extend(self.folder_rules(fld));
                 };
-
-                // if multiple folders are named in the folders (with a s) paratmer then check them all
-                if let Some(folder_list) = &fld.folders {
-                    if folder_list.contains(&folder) {
-                        // matching, then adding
-                        rules.extend_from_slice(&fld.rules)
-                    }
-                }
                 rules
             })
             .collect()
     }
 
-    pub fn print(&self) {
+    pub fn retention_for_folder(&self, folder: &String) -> Vec<RetentionRule> {
+        self.folders
+            .iter()
+            .filter(|fld| fld.source_folders().contains(folder))
+            .flat_map(|fld| fld.retention.clone().unwrap_or_default())
+            .collect()
+    }
+
+    pub fn dedupe_for_folder(&self, folder: &String) -> Option<DedupeRule> {
+        self.folders
+            .iter()
+            .filter(|fld| fld.source_folders().contains(folder))
+            .find_map(|fld| fld.dedupe.clone())
+    }
+
+    pub fn print(&self, tag: &Option<TagExpression>) {
+        if let Some(tag) = tag {
+            println!("Rules selected by: {}", tag);
+        }
         for folder in &self.folders {
-            folder.print();
+            folder.print(tag);
         }
+        for group in self.groups.iter().flatten() {
+            println!("Group: {}", &group.name);
+            for rule in group.rules.iter().filter(|rule| rule.match_tag(tag)) {
+                rule.print();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retention(older_than_days: u32, keep_flagged: bool, filter: Option<&str>) -> RetentionRule {
+        RetentionRule {
+            name: "old".to_string(),
+            older_than_days,
+            action: RetentionAction::Archive,
+            target: Some("Archive/{year}".to_string()),
+            filter: filter.map(|filter| filter.to_string()),
+            enable: true,
+            keep_flagged,
+        }
+    }
+
+    // write rule files in a directory of their own, and load the first one
+    fn load_files(name: &str, files: &[(&str, &str)]) -> Result<RulesSet> {
+        let dir = std::env::temp_dir().join(format!("imap_sorter_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file_name, content) in files {
+            std::fs::write(dir.join(file_name), content).unwrap();
+        }
+        let rules_set = RulesSet::load(dir.join(files[0].0).to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        rules_set
+    }
+
+    // a folder with one rule, named after the file it comes from
+    fn folder(name: &str) -> String {
+        format!(
+            "
+folders:
+  - folder: INBOX
+    rules:
+      - name: {name}
+        filter: ALL
+        target: {name}
+        enable: true
+"
+        )
+    }
+
+    #[test]
+    fn retention_filters() {
+        let today = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+        let cases = [
+            (retention(30, false, None), "BEFORE 09-Feb-2024"),
+            (retention(30, true, None), "BEFORE 09-Feb-2024 UNFLAGGED"),
+            // crossing a leap day and a year
+            (retention(10, false, None), "BEFORE 29-Feb-2024"),
+            (retention(70, false, None), "BEFORE 31-Dec-2023"),
+            (retention(0, false, None), "BEFORE 10-Mar-2024"),
+            (
+                retention(30, true, Some("SEEN")),
+                "BEFORE 09-Feb-2024 UNFLAGGED SEEN",
+            ),
+            (
+                retention(30, false, Some("FROM \"shop\"")),
+                "BEFORE 09-Feb-2024 FROM \"shop\"",
+            ),
+        ];
+        for (rule, filter) in cases {
+            assert_eq!(rule.search_filter(today), filter, "{}", rule.as_string());
+        }
+    }
+
+    #[test]
+    fn folder_patterns() {
+        let cases = [
+            // exact names
+            ("INBOX", "INBOX", "/", true),
+            ("INBOX", "INBOX/Sub", "/", false),
+            ("Lists/rust", "Lists/rust", "/", true),
+            ("Lists/rust", "Lists/go", "/", false),
+            // % stops at the delimiter
+            ("Lists/%", "Lists/rust", "/", true),
+            ("Lists/%", "Lists/rust/announce", "/", false),
+            ("Lists/%", "Lists/", "/", true),
+            ("Lists/%", "Lists", "/", false),
+            ("%", "INBOX", "/", true),
+            ("%", "INBOX/Sub", "/", false),
+            ("%/rust", "Lists/rust", "/", true),
+            ("%.rust", "Lists.rust", ".", true),
+            ("Lists.%", "Lists.rust.announce", ".", false),
+            // * crosses it
+            ("Lists/*", "Lists/rust/announce", "/", true),
+            ("*", "Lists/rust/announce", "/", true),
+            ("*/announce", "Lists/rust/announce", "/", true),
+            ("Lists.*", "Lists.rust.announce", ".", true),
+            ("Archive/*", "Lists/rust", "/", false),
+            ("Archive/20*", "Archive/2023", "/", true),
+        ];
+        for (pattern, name, delimiter, matched) in cases {
+            assert_eq!(
+                folder_matches(pattern, name, delimiter),
+                matched,
+                "{} against {} with {}",
+                pattern,
+                name,
+                delimiter
+            );
+        }
+    }
+
+    #[test]
+    fn file_including_itself_is_a_cycle() {
+        let main = format!("include: [main.yaml]\n{}", folder("main"));
+        let error = load_files("include_self", &[("main.yaml", &main)]).unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(message.contains("include cycle detected"), "{}", message);
+        assert!(message.contains("main.yaml -> "), "{}", message);
+    }
+
+    #[test]
+    fn cycle_through_two_files_is_detected() {
+        let main = format!("include: [other.yaml]\n{}", folder("main"));
+        let other = format!("include: [main.yaml]\n{}", folder("other"));
+        let error = load_files(
+            "include_cycle",
+            &[("main.yaml", &main), ("other.yaml", &other)],
+        )
+        .unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(message.contains("include cycle detected"), "{}", message);
+        assert!(
+            message.contains("main.yaml -> ") && message.contains("other.yaml -> "),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn file_included_twice_is_merged_once() {
+        let main = format!("include: [a.yaml, b.yaml]\n{}", folder("main"));
+        let a = format!("include: [shared.yaml]\n{}", folder("a"));
+        let b = format!("include: [shared.yaml]\n{}", folder("b"));
+        let files = [
+            ("main.yaml", main.as_str()),
+            ("a.yaml", &a),
+            ("b.yaml", &b),
+            ("shared.yaml", &folder("shared")),
+        ];
+        let rules_set = load_files("include_twice", &files).unwrap();
+        let names: Vec<&str> = rules_set
+            .folders
+            .iter()
+            .flat_map(|folder| &folder.rules)
+            .map(|rule| rule.name.as_str())
+            .collect();
+        assert_eq!(names, ["shared", "a", "b", "main"]);
     }
 }
@@ -0,0 +1,238 @@
+use crate::rules::Rule;
+use crate::sorter::RunReport;
+use anyhow::Context;
+use chrono::{DateTime, Duration, FixedOffset, Local};
+use std::collections::{BTreeMap, HashSet};
+use std::str::FromStr;
+
+// rules with a schedule only run when they are due, their last runs are kept in a state file
+// so that one frequent invocation, or the daemon, can run every rule at its own pace
+
+// `schedule: "0 7 * * 1-5"` runs at the times of a cron expression,
+// `schedule: { every: 1h }` at most once per period
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Schedule {
+    Every { every: String },
+    Cron(String),
+}
+
+// a period like 90s, 30m, 1h, 1h30m, 2d or 1w
+pub fn parse_period(text: &str) -> anyhow::Result<Duration> {
+    let mut period = Duration::zero();
+    let mut number = String::new();
+    for c in text.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let count: i64 = number
+            .parse()
+            .with_context(|| format!("invalid period {}", text))?;
+        period += match c {
+            's' => Duration::seconds(count),
+            'm' => Duration::minutes(count),
+            'h' => Duration::hours(count),
+            'd' => Duration::days(count),
+            'w' => Duration::weeks(count),
+            _ => anyhow::bail!("invalid unit {} in period {}, use s, m, h, d or w", c, text),
+        };
+        number.clear();
+    }
+    if !number.is_empty() || period <= Duration::zero() {
+        anyhow::bail!("invalid period {}, like 30m, 1h or 2d", text);
+    }
+    Ok(period)
+}
+
+// cron expressions of five fields have no seconds, the cron crate wants them
+fn parse_cron(expression: &str) -> anyhow::Result<cron::Schedule> {
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+    cron::Schedule::from_str(&expression)
+        .map_err(|error| anyhow::anyhow!("invalid cron expression {}: {}", expression, error))
+}
+
+impl Schedule {
+    pub fn check(&self) -> anyhow::Result<()> {
+        match self {
+            Schedule::Every { every } => parse_period(every).map(|_| ()),
+            Schedule::Cron(expression) => parse_cron(expression).map(|_| ()),
+        }
+    }
+
+    // tell if something last run at `last` is due at `now`, what never ran is
+    pub fn is_due(&self, last: Option<DateTime<FixedOffset>>, now: DateTime<Local>) -> bool {
+        let Some(last) = last else {
+            return true;
+        };
+        match self {
+            Schedule::Every { every } => match parse_period(every) {
+                Ok(period) => now.fixed_offset() - last >= period,
+                Err(_) => true,
+            },
+            Schedule::Cron(expression) => match parse_cron(expression) {
+                Ok(cron) => cron
+                    .after(&last)
+                    .next()
+                    .is_some_and(|next| next <= now.fixed_offset()),
+                Err(_) => true,
+            },
+        }
+    }
+
+    pub fn as_string(&self) -> String {
+        match self {
+            Schedule::Every { every } => format!("every {}", every),
+            Schedule::Cron(expression) => expression.clone(),
+        }
+    }
+}
+
+// when each rule last ran, by folder then rule, as written in the state file
+#[derive(Debug, Default)]
+pub struct ScheduleState {
+    path: String,
+    last_runs: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl ScheduleState {
+    // a missing state file means nothing ran yet
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let last_runs = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("invalid schedule state in {}", path))?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => {
+                return Err(error).with_context(|| format!("cannot read schedule state {}", path))
+            }
+        };
+        Ok(ScheduleState {
+            path: path.to_string(),
+            last_runs,
+        })
+    }
+
+    pub fn last_run(&self, folder: &str, rule: &str) -> Option<DateTime<FixedOffset>> {
+        let last_run = self.last_runs.get(folder)?.get(rule)?;
+        DateTime::parse_from_rfc3339(last_run).ok()
+    }
+
+    // rules without a schedule are always due
+    pub fn is_due(&self, folder: &str, rule: &Rule) -> bool {
+        match &rule.schedule {
+            Some(schedule) => schedule.is_due(self.last_run(folder, &rule.name), Local::now()),
+            None => true,
+        }
+    }
+
+    // remember the scheduled rules that ran without errors, as of the start of the run
+    pub fn record(&mut self, report: &RunReport, scheduled: &HashSet<String>) {
+        for folder in &report.folders {
+            for rule in &folder.rules {
+                if scheduled.contains(&rule.rule)
+                    && rule.errors.is_empty()
+                    && rule.skipped.is_none()
+                {
+                    self.last_runs
+                        .entry(folder.folder.clone())
+                        .or_default()
+                        .insert(rule.rule.clone(), report.started.clone());
+                }
+            }
+        }
+    }
+
+    // written aside then renamed, so that a crash never leaves half a file
+    pub fn save(&self) -> anyhow::Result<()> {
+        let temporary = format!("{}.tmp", self.path);
+        let content = serde_json::to_string_pretty(&self.last_runs)?;
+        std::fs::write(&temporary, content)
+            .with_context(|| format!("cannot write schedule state to {}", temporary))?;
+        std::fs::rename(&temporary, &self.path)
+            .with_context(|| format!("cannot write schedule state to {}", self.path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a time of 2024 in UTC, like "05-02 07:00"
+    fn time(text: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(&format!("2024-{}:00+00:00", text.replace(' ', "T"))).unwrap()
+    }
+
+    #[test]
+    fn periods() {
+        let cases = [
+            ("90s", Some(Duration::seconds(90))),
+            ("30m", Some(Duration::minutes(30))),
+            ("90m", Some(Duration::minutes(90))),
+            ("1h", Some(Duration::hours(1))),
+            (" 1h30m ", Some(Duration::minutes(90))),
+            ("2d", Some(Duration::days(2))),
+            ("1w", Some(Duration::weeks(1))),
+            ("", None),
+            ("0m", None),
+            ("10", None),
+            ("h", None),
+            ("1y", None),
+            ("1H", None),
+            ("1h 30m", None),
+            ("-1h", None),
+        ];
+        for (text, period) in cases {
+            assert_eq!(parse_period(text).ok(), period, "period {:?}", text);
+        }
+    }
+
+    #[test]
+    fn due_checks() {
+        let every = Schedule::Every {
+            every: "1h".to_string(),
+        };
+        let cron = Schedule::Cron("0 7 * * *".to_string());
+        let cases = [
+            // never ran
+            (&every, None, "05-02 07:00", true),
+            (&cron, None, "05-02 07:00", true),
+            // ran exactly one period ago
+            (&every, Some("05-02 06:00"), "05-02 07:00", true),
+            (&cron, Some("05-01 07:00"), "05-02 07:00", true),
+            // not yet
+            (&every, Some("05-02 06:01"), "05-02 07:00", false),
+            (&cron, Some("05-01 07:00"), "05-02 06:59", false),
+            (&cron, Some("05-02 07:00"), "05-02 07:00", false),
+            // long overdue
+            (&every, Some("04-01 00:00"), "05-02 07:00", true),
+            (&cron, Some("04-01 00:00"), "05-02 07:00", true),
+        ];
+        for (schedule, last, now, due) in cases {
+            assert_eq!(
+                schedule.is_due(last.map(time), time(now).with_timezone(&Local)),
+                due,
+                "{} last ran {:?} at {}",
+                schedule.as_string(),
+                last,
+                now
+            );
+        }
+    }
+
+    #[test]
+    fn cron_expressions_take_five_or_six_fields() {
+        assert!(Schedule::Cron("0 7 * * 1-5".to_string()).check().is_ok());
+        assert!(Schedule::Cron("30 0 7 * * 1-5".to_string()).check().is_ok());
+        assert!(Schedule::Cron("every morning".to_string()).check().is_err());
+        assert!(Schedule::Every {
+            every: "1 hour".to_string()
+        }
+        .check()
+        .is_err());
+    }
+}
@@ -0,0 +1,974 @@
+use crate::rules::{FolderRule, Rule, RulesSet};
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+
+// ---------------------------------------------------------------------------
+// export: rules to Sieve (rfc5228)
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, PartialEq)]
+enum SearchToken {
+    Atom(String),
+    Quoted(String),
+    Open,
+    Close,
+}
+
+// split an IMAP search filter in atoms, quoted strings and parenthesis
+fn tokenize_search(filter: &str) -> Result<Vec<SearchToken>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = filter.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(SearchToken::Open),
+            ')' => tokens.push(SearchToken::Close),
+            '"' => {
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => quoted.extend(chars.next()),
+                        Some('"') => break,
+                        Some(c) => quoted.push(c),
+                        None => return Err("unterminated quoted string".to_string()),
+                    }
+                }
+                tokens.push(SearchToken::Quoted(quoted));
+            }
+            c => {
+                let mut atom = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || next == '(' || next == ')' {
+                        break;
+                    }
+                    atom.push(next);
+                    chars.next();
+                }
+                tokens.push(SearchToken::Atom(atom));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// quoted string, escaped the same way in Sieve, IMAP and ManageSieve
+pub(crate) fn quoted(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// translate IMAP search keys into Sieve tests, one search key at a time
+struct SearchTranslator {
+    tokens: Vec<SearchToken>,
+    position: usize,
+    extensions: BTreeSet<&'static str>,
+}
+
+impl SearchTranslator {
+    fn next_token(&mut self) -> Option<&SearchToken> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn string(&mut self, key: &str) -> Result<String, String> {
+        match self.next_token() {
+            Some(SearchToken::Atom(value)) | Some(SearchToken::Quoted(value)) => Ok(value.clone()),
+            _ => Err(format!("missing value for {}", key)),
+        }
+    }
+
+    fn number(&mut self, key: &str) -> Result<String, String> {
+        let value = self.string(key)?;
+        match value.parse::<u64>() {
+            Ok(_) => Ok(value),
+            Err(_) => Err(format!("invalid number for {}: {}", key, value)),
+        }
+    }
+
+    fn header_test(&mut self, header: &str, key: &str) -> Result<String, String> {
+        let value = self.string(key)?;
+        Ok(format!(
+            "header :contains {} {}",
+            quoted(header),
+            quoted(&value)
+        ))
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        let atom = match self.next_token() {
+            Some(SearchToken::Open) => {
+                let mut tests = Vec::new();
+                while self.tokens.get(self.position) != Some(&SearchToken::Close) {
+                    if self.position >= self.tokens.len() {
+                        return Err("missing closing parenthesis".to_string());
+                    }
+                    tests.push(self.key()?);
+                }
+                self.position += 1;
+                return Ok(all_of(tests));
+            }
+            Some(SearchToken::Atom(atom)) => atom.to_uppercase(),
+            Some(token) => return Err(format!("unexpected {:?}", token)),
+            None => return Err("missing search key".to_string()),
+        };
+        match atom.as_str() {
+            "ALL" => Ok("true".to_string()),
+            "FROM" | "TO" | "CC" | "BCC" | "SUBJECT" => {
+                self.header_test(&atom.to_lowercase(), &atom)
+            }
+            "HEADER" => {
+                let header = self.string(&atom)?;
+                self.header_test(&header, &atom)
+            }
+            "BODY" | "TEXT" => {
+                let value = self.string(&atom)?;
+                self.extensions.insert("body");
+                Ok(format!("body :text :contains {}", quoted(&value)))
+            }
+            "LARGER" => Ok(format!("size :over {}", self.number(&atom)?)),
+            "SMALLER" => Ok(format!("size :under {}", self.number(&atom)?)),
+            "NOT" => Ok(format!("not {}", self.key()?)),
+            "OR" => {
+                let first = self.key()?;
+                let second = self.key()?;
+                Ok(format!("anyof({}, {})", first, second))
+            }
+            _ => Err(format!("unsupported search key {}", atom)),
+        }
+    }
+}
+
+fn all_of(mut tests: Vec<String>) -> String {
+    if tests.len() == 1 {
+        tests.remove(0)
+    } else {
+        format!("allof({})", tests.join(", "))
+    }
+}
+
+// Sieve tests, all to match, equivalent to an IMAP search filter, and the extensions they need
+fn search_to_sieve(filter: &str) -> Result<(Vec<String>, BTreeSet<&'static str>), String> {
+    let mut translator = SearchTranslator {
+        tokens: tokenize_search(filter)?,
+        position: 0,
+        extensions: BTreeSet::new(),
+    };
+    let mut tests = Vec::new();
+    while translator.position < translator.tokens.len() {
+        tests.push(translator.key()?);
+    }
+    if tests.is_empty() {
+        return Err("empty filter".to_string());
+    }
+    Ok((tests, translator.extensions))
+}
+
+// Sieve block of one rule, and the extensions it needs
+fn rule_to_sieve(rule: &Rule) -> Result<(String, BTreeSet<&'static str>), String> {
+    if rule.classifier.is_some() {
+        return Err("classifier condition cannot be expressed in Sieve".to_string());
+    }
+    if rule.target_uses_list_id() {
+        return Err("{list_id} target cannot be expressed in Sieve".to_string());
+    }
+    if rule.target_account().is_some() {
+        return Err("target on another account cannot be expressed in Sieve".to_string());
+    }
+    let mut tests = Vec::new();
+    let mut extensions = BTreeSet::from(["fileinto"]);
+    if !rule.filter.trim().is_empty() {
+        let (filter_tests, needed) = search_to_sieve(&rule.filter)?;
+        tests.extend(filter_tests);
+        extensions.extend(needed);
+    }
+    if let Some(list) = &rule.list {
+        tests.push(format!("header :contains \"list-id\" {}", quoted(list)));
+    }
+    let mut block = format!("# rule: {}\nif {} {{\n", rule.name, all_of(tests));
+    for flag in rule.flags.iter().flatten() {
+        extensions.insert("imap4flags");
+        block.push_str(&format!("    addflag {};\n", quoted(flag)));
+    }
+    block.push_str(&format!("    fileinto {};\n", quoted(&rule.target)));
+    block.push_str("    stop;\n}\n");
+    Ok((block, extensions))
+}
+
+// Sieve script for the INBOX rules, along with the rules that could not be exported
+pub fn export_sieve(rules_set: &RulesSet) -> (String, Vec<(String, String)>) {
+    let mut blocks = Vec::new();
+    let mut extensions = BTreeSet::new();
+    let mut unsupported = Vec::new();
+    for rule in rules_set.rules_for_folder(&"INBOX".to_string()) {
+        if !rule.enable {
+            unsupported.push((rule.name.clone(), "rule is disabled".to_string()));
+            continue;
+        }
+        match rule_to_sieve(&rule) {
+            Ok((block, needed)) => {
+                blocks.push(block);
+                extensions.extend(needed);
+            }
+            Err(reason) => unsupported.push((rule.name.clone(), reason)),
+        }
+    }
+    let mut script = String::from("# generated by imap_sorter\n");
+    if !extensions.is_empty() {
+        let extensions: Vec<String> = extensions.iter().map(|e| quoted(e)).collect();
+        script.push_str(&format!("require [{}];\n", extensions.join(", ")));
+    }
+    for block in blocks {
+        script.push('\n');
+        script.push_str(&block);
+    }
+    (script, unsupported)
+}
+
+// ---------------------------------------------------------------------------
+// import: a common subset of Sieve to rules
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    Tag(String),
+    Str(String),
+    Number(u64),
+    Comment(String),
+    Symbol(char),
+}
+
+fn tokenize_sieve(script: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = script.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '#' => {
+                let start = i + 1;
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                let comment: String = chars[start..i].iter().collect();
+                tokens.push(Token::Comment(comment.trim().to_string()));
+            }
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                i += 2;
+                while i + 1 < chars.len() && !(chars[i] == '*' && chars[i + 1] == '/') {
+                    i += 1;
+                }
+                i += 2;
+            }
+            '"' => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        Some('\\') => {
+                            value.extend(chars.get(i + 1));
+                            i += 2;
+                        }
+                        Some('"') => {
+                            i += 1;
+                            break;
+                        }
+                        Some(c) => {
+                            value.push(*c);
+                            i += 1;
+                        }
+                        None => anyhow::bail!("unterminated string in Sieve script"),
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            ':' => {
+                let start = i + 1;
+                i += 1;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let tag: String = chars[start..i].iter().collect();
+                tokens.push(Token::Tag(tag.to_lowercase()));
+            }
+            c if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let digits: String = chars[start..i].iter().collect();
+                let mut number: u64 = digits.parse()?;
+                // quantifiers of rfc5228 section 2.4.1
+                match chars.get(i).map(|c| c.to_ascii_uppercase()) {
+                    Some('K') => number *= 1024,
+                    Some('M') => number *= 1024 * 1024,
+                    Some('G') => number *= 1024 * 1024 * 1024,
+                    _ => i -= 1,
+                }
+                i += 1;
+                tokens.push(Token::Number(number));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let identifier: String = chars[start..i].iter().collect();
+                // multi-line string, up to a line with a single dot
+                if identifier.eq_ignore_ascii_case("text") && chars.get(i) == Some(&':') {
+                    let rest: String = chars[i + 1..].iter().collect();
+                    let (_, body) = rest.split_once('\n').unwrap_or(("", ""));
+                    let end = body.find("\n.\n").or_else(|| body.find("\n.\r\n"));
+                    let Some(end) = end else {
+                        anyhow::bail!("unterminated multi-line string in Sieve script");
+                    };
+                    tokens.push(Token::Str(body[..end].to_string()));
+                    let consumed = rest.len() - body.len() + end + 3;
+                    i += 1 + rest[..consumed.min(rest.len())].chars().count();
+                    continue;
+                }
+                tokens.push(Token::Identifier(identifier.to_lowercase()));
+            }
+            '[' | ']' | '(' | ')' | '{' | '}' | ',' | ';' => {
+                tokens.push(Token::Symbol(c));
+                i += 1;
+            }
+            c => anyhow::bail!("unexpected character in Sieve script: {}", c),
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum Argument {
+    Tag(String),
+    // :comparator and its name, taken together so that they do not count as a string argument
+    Comparator(String),
+    Strings(Vec<String>),
+    Number(u64),
+}
+
+#[derive(Debug, Clone)]
+struct Test {
+    name: String,
+    arguments: Vec<Argument>,
+    tests: Vec<Test>,
+}
+
+#[derive(Debug)]
+struct Command {
+    name: String,
+    comment: Option<String>,
+    arguments: Vec<Argument>,
+    tests: Vec<Test>,
+    block: Option<Vec<Command>>,
+}
+
+struct SieveParser {
+    tokens: Vec<Token>,
+    position: usize,
+    last_comment: Option<String>,
+}
+
+impl SieveParser {
+    // next significant token, remembering the last comment seen
+    fn peek(&mut self) -> Option<Token> {
+        while let Some(Token::Comment(comment)) = self.tokens.get(self.position) {
+            self.last_comment = Some(comment.clone());
+            self.position += 1;
+        }
+        self.tokens.get(self.position).cloned()
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<()> {
+        match self.next() {
+            Some(Token::Symbol(c)) if c == symbol => Ok(()),
+            token => anyhow::bail!("expected {} in Sieve script, found {:?}", symbol, token),
+        }
+    }
+
+    fn arguments(&mut self) -> Result<Vec<Argument>> {
+        let mut arguments = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Tag(tag)) if tag == "comparator" => {
+                    self.next();
+                    match self.peek() {
+                        Some(Token::Str(name)) => arguments.push(Argument::Comparator(name)),
+                        token => anyhow::bail!("expected a comparator name, found {:?}", token),
+                    }
+                }
+                Some(Token::Tag(tag)) => arguments.push(Argument::Tag(tag)),
+                Some(Token::Str(value)) => arguments.push(Argument::Strings(vec![value])),
+                Some(Token::Number(number)) => arguments.push(Argument::Number(number)),
+                Some(Token::Symbol('[')) => {
+                    self.next();
+                    let mut values = Vec::new();
+                    loop {
+                        match self.next() {
+                            Some(Token::Str(value)) => values.push(value),
+                            Some(Token::Symbol(',')) => {}
+                            Some(Token::Symbol(']')) => break,
+                            token => anyhow::bail!("unexpected {:?} in string list", token),
+                        }
+                    }
+                    arguments.push(Argument::Strings(values));
+                    continue;
+                }
+                _ => return Ok(arguments),
+            }
+            self.next();
+        }
+    }
+
+    fn test(&mut self) -> Result<Test> {
+        let name = match self.next() {
+            Some(Token::Identifier(name)) => name,
+            token => anyhow::bail!("expected a test in Sieve script, found {:?}", token),
+        };
+        let arguments = self.arguments()?;
+        let tests = self.nested_tests()?;
+        Ok(Test {
+            name,
+            arguments,
+            tests,
+        })
+    }
+
+    // single test, or list of tests in parenthesis
+    fn nested_tests(&mut self) -> Result<Vec<Test>> {
+        match self.peek() {
+            Some(Token::Identifier(_)) => Ok(vec![self.test()?]),
+            Some(Token::Symbol('(')) => {
+                self.next();
+                let mut tests = vec![self.test()?];
+                while self.peek() == Some(Token::Symbol(',')) {
+                    self.next();
+                    tests.push(self.test()?);
+                }
+                self.expect(')')?;
+                Ok(tests)
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn commands(&mut self) -> Result<Vec<Command>> {
+        let mut commands = Vec::new();
+        loop {
+            self.last_comment = None;
+            let name = match self.peek() {
+                None | Some(Token::Symbol('}')) => return Ok(commands),
+                Some(Token::Identifier(name)) => name,
+                Some(token) => {
+                    anyhow::bail!("expected a command in Sieve script, found {:?}", token)
+                }
+            };
+            let comment = self.last_comment.take();
+            self.next();
+            let arguments = self.arguments()?;
+            let tests = self.nested_tests()?;
+            let block = match self.next() {
+                Some(Token::Symbol(';')) => None,
+                Some(Token::Symbol('{')) => {
+                    let block = self.commands()?;
+                    self.expect('}')?;
+                    Some(block)
+                }
+                token => anyhow::bail!("expected ; or {{ after {}, found {:?}", name, token),
+            };
+            commands.push(Command {
+                name,
+                comment,
+                arguments,
+                tests,
+                block,
+            });
+        }
+    }
+}
+
+// one search key out of a list of keys that must all match
+pub fn group(mut keys: Vec<String>) -> String {
+    if keys.len() == 1 {
+        keys.remove(0)
+    } else {
+        format!("({})", keys.join(" "))
+    }
+}
+
+// search key true when any of the alternatives is
+pub(crate) fn or_chain(mut alternatives: Vec<Vec<String>>) -> Vec<String> {
+    if alternatives.len() == 1 {
+        return alternatives.remove(0);
+    }
+    let first = group(alternatives.remove(0));
+    vec![format!("OR {} {}", first, group(or_chain(alternatives)))]
+}
+
+fn strings(arguments: &[Argument]) -> Vec<Vec<String>> {
+    arguments
+        .iter()
+        .filter_map(|argument| match argument {
+            Argument::Strings(values) => Some(values.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn tags(arguments: &[Argument]) -> Vec<String> {
+    arguments
+        .iter()
+        .filter_map(|argument| match argument {
+            Argument::Tag(tag) => Some(tag.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+// comparator of a test, the default one is case insensitive like IMAP searches
+fn comparator(arguments: &[Argument]) -> Option<&str> {
+    arguments.iter().find_map(|argument| match argument {
+        Argument::Comparator(name) => Some(name.as_str()),
+        _ => None,
+    })
+}
+
+// value to search for, according to the match type of the test
+// IMAP only searches substrings, notes tell where that matches more than the Sieve test did
+fn match_value(
+    test: &Test,
+    tags: &[String],
+    value: &str,
+    notes: &mut Vec<String>,
+) -> Result<String, String> {
+    if tags.iter().any(|tag| tag == "regex") {
+        return Err("regex match is not supported".to_string());
+    }
+    if let Some(comparator) = comparator(&test.arguments) {
+        if !comparator.eq_ignore_ascii_case("i;ascii-casemap") {
+            notes.push(format!(
+                "{} comparator {} searched case insensitively",
+                test.name, comparator
+            ));
+        }
+    }
+    if tags.iter().any(|tag| tag == "matches") {
+        let stripped = value.trim_matches(['*', '?']);
+        if stripped.contains(['*', '?']) {
+            return Err(format!("wildcards inside {} are not supported", value));
+        }
+        if value != format!("*{}*", stripped) {
+            notes.push(format!(
+                "{} :matches \"{}\" searched as contains \"{}\"",
+                test.name, value, stripped
+            ));
+        }
+        return Ok(stripped.to_string());
+    }
+    // :is is the default match type
+    if !tags.iter().any(|tag| tag == "contains") {
+        notes.push(format!(
+            "{} :is \"{}\" searched as contains",
+            test.name, value
+        ));
+    }
+    Ok(value.to_string())
+}
+
+fn header_key(header: &str, value: &str) -> String {
+    match header.to_lowercase().as_str() {
+        "from" | "to" | "cc" | "bcc" | "subject" => {
+            format!("{} {}", header.to_uppercase(), quoted(value))
+        }
+        _ => format!("HEADER {} {}", header, quoted(value)),
+    }
+}
+
+// IMAP search keys, all to match, equivalent to a Sieve test, notes about approximations are appended
+fn test_to_search(test: &Test, notes: &mut Vec<String>) -> Result<Vec<String>, String> {
+    let tags = tags(&test.arguments);
+    match test.name.as_str() {
+        "true" => Ok(vec!["ALL".to_string()]),
+        "not" => match test.tests.as_slice() {
+            [inner] => Ok(vec![format!(
+                "NOT {}",
+                group(test_to_search(inner, notes)?)
+            )]),
+            _ => Err("not needs one test".to_string()),
+        },
+        "anyof" => Ok(or_chain(
+            test.tests
+                .iter()
+                .map(|test| test_to_search(test, notes))
+                .collect::<Result<Vec<Vec<String>>, String>>()?,
+        )),
+        "allof" => Ok(test
+            .tests
+            .iter()
+            .map(|test| test_to_search(test, notes))
+            .collect::<Result<Vec<Vec<String>>, String>>()?
+            .concat()),
+        "header" | "address" => {
+            let [headers, values] = strings(&test.arguments)
+                .try_into()
+                .map_err(|_| format!("{} needs header names and values", test.name))?;
+            let mut alternatives = Vec::new();
+            for header in &headers {
+                for value in &values {
+                    let mut value = match_value(test, &tags, value, notes)?;
+                    if tags.iter().any(|tag| tag == "domain") {
+                        value = format!("@{}", value);
+                    } else if tags.iter().any(|tag| tag == "localpart") {
+                        value = format!("{}@", value);
+                    }
+                    alternatives.push(vec![header_key(header, &value)]);
+                }
+            }
+            if alternatives.is_empty() {
+                return Err(format!("{} without value", test.name));
+            }
+            Ok(or_chain(alternatives))
+        }
+        "exists" => Ok(strings(&test.arguments)
+            .concat()
+            .iter()
+            .map(|header| format!("HEADER {} \"\"", header))
+            .collect()),
+        "size" => {
+            let number = test.arguments.iter().find_map(|argument| match argument {
+                Argument::Number(number) => Some(*number),
+                _ => None,
+            });
+            match (tags.first().map(|tag| tag.as_str()), number) {
+                (Some("over"), Some(number)) => Ok(vec![format!("LARGER {}", number)]),
+                (Some("under"), Some(number)) => Ok(vec![format!("SMALLER {}", number)]),
+                _ => Err("size needs :over or :under and a number".to_string()),
+            }
+        }
+        "body" => {
+            let alternatives = strings(&test.arguments)
+                .concat()
+                .iter()
+                .map(|value| {
+                    let value = match_value(test, &tags, value, notes)?;
+                    Ok(vec![format!("BODY {}", quoted(&value))])
+                })
+                .collect::<Result<Vec<Vec<String>>, String>>()?;
+            if alternatives.is_empty() {
+                return Err("body without value".to_string());
+            }
+            Ok(or_chain(alternatives))
+        }
+        name => Err(format!("unsupported test {}", name)),
+    }
+}
+
+// rule equivalent to the actions of an if block, with notes about what changed on the way
+fn block_to_rule(
+    name: String,
+    filter: String,
+    block: &[Command],
+    notes: &mut Vec<String>,
+) -> Result<Rule, String> {
+    let mut rule = Rule {
+        name,
+        filter,
+        enable: true,
+        ..Default::default()
+    };
+    let mut target = None;
+    for command in block {
+        let values = strings(&command.arguments).concat();
+        match command.name.as_str() {
+            "fileinto" => target = values.first().cloned(),
+            "addflag" | "setflag" => rule.flags.get_or_insert_with(Vec::new).extend(
+                values
+                    .iter()
+                    .flat_map(|flags| flags.split_whitespace().map(|flag| flag.to_string())),
+            ),
+            "stop" => {}
+            // a rule moves messages, no copy is left behind
+            "keep" => notes.push("keep dropped, messages are moved without a copy".to_string()),
+            name => return Err(format!("unsupported action {}", name)),
+        }
+    }
+    match target {
+        Some(target) => {
+            rule.target = target;
+            Ok(rule)
+        }
+        None => Err("no fileinto action".to_string()),
+    }
+}
+
+// rules for INBOX equivalent to a Sieve script, along with what could not be imported as is
+pub fn import_sieve(script: &str) -> Result<(RulesSet, Vec<(String, String)>)> {
+    let mut parser = SieveParser {
+        tokens: tokenize_sieve(script)?,
+        position: 0,
+        last_comment: None,
+    };
+    let commands = parser.commands()?;
+    if parser.peek().is_some() {
+        anyhow::bail!("unexpected }} in Sieve script");
+    }
+
+    let mut rules = Vec::new();
+    let mut reports = Vec::new();
+    let mut index = 0;
+    for command in &commands {
+        if command.name == "require" {
+            continue;
+        }
+        index += 1;
+        let name = command
+            .comment
+            .as_deref()
+            .and_then(|comment| comment.strip_prefix("rule:"))
+            .map(|name| name.trim().to_string())
+            .unwrap_or(format!("sieve rule {}", index));
+        match command.name.as_str() {
+            "if" | "elsif" => {}
+            other => {
+                reports.push((name, format!("not imported: unsupported command {}", other)));
+                continue;
+            }
+        }
+        let mut notes = Vec::new();
+        let result = match command.tests.as_slice() {
+            [test] => test_to_search(test, &mut notes).map(|keys| keys.join(" ")),
+            _ => Err("if needs one test".to_string()),
+        }
+        .and_then(|filter| {
+            block_to_rule(
+                name.clone(),
+                filter,
+                command.block.as_deref().unwrap_or(&[]),
+                &mut notes,
+            )
+        });
+        match result {
+            Ok(rule) => rules.push(rule),
+            Err(reason) => reports.push((name.clone(), format!("not imported: {}", reason))),
+        }
+        for note in notes {
+            reports.push((name.clone(), note));
+        }
+    }
+
+    let rules_set = RulesSet {
+        folders: vec![FolderRule {
+            folder: "INBOX".to_string(),
+            rules,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    Ok((rules_set, reports))
+}
+
+pub fn read_sieve_file(path: &str) -> Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("Failed to open file: {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules_set(rules: Vec<Rule>) -> RulesSet {
+        RulesSet {
+            folders: vec![FolderRule {
+                folder: "INBOX".to_string(),
+                rules,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn rule(name: &str, filter: &str, target: &str) -> Rule {
+        Rule {
+            name: name.to_string(),
+            filter: filter.to_string(),
+            target: target.to_string(),
+            enable: true,
+            ..Default::default()
+        }
+    }
+
+    // (filter, target) of each rule, or (rule, report)
+    type Pairs = Vec<(String, String)>;
+
+    // filters and reports of the rules imported from a script
+    fn import(script: &str) -> (Pairs, Pairs) {
+        let (rules_set, reports) = import_sieve(script).unwrap();
+        let rules = rules_set.folders[0]
+            .rules
+            .iter()
+            .map(|rule| (rule.filter.clone(), rule.target.clone()))
+            .collect();
+        (rules, reports)
+    }
+
+    fn pair(first: &str, second: &str) -> (String, String) {
+        (first.to_string(), second.to_string())
+    }
+
+    #[test]
+    fn contains_tests_import_as_they_are() {
+        let (rules, reports) = import(
+            r#"require ["fileinto"];
+            # rule: vendor
+            if anyof(header :contains "from" "a@b.c", not size :over 10K) {
+                fileinto "Vendors";
+                stop;
+            }"#,
+        );
+        assert_eq!(
+            rules,
+            vec![pair("OR FROM \"a@b.c\" NOT LARGER 10240", "Vendors")]
+        );
+        assert!(reports.is_empty());
+    }
+
+    #[test]
+    fn approximations_are_reported() {
+        let (rules, reports) = import(
+            r#"if header :is "subject" "Invoice" { fileinto "A"; }
+            if address :matches "from" "news@*" { fileinto "B"; }
+            if header :contains "subject" "x" { fileinto "C"; keep; }"#,
+        );
+        assert_eq!(
+            rules,
+            vec![
+                pair("SUBJECT \"Invoice\"", "A"),
+                pair("FROM \"news@\"", "B"),
+                pair("SUBJECT \"x\"", "C")
+            ]
+        );
+        assert_eq!(
+            reports,
+            vec![
+                pair(
+                    "sieve rule 1",
+                    "header :is \"Invoice\" searched as contains"
+                ),
+                pair(
+                    "sieve rule 2",
+                    "address :matches \"news@*\" searched as contains \"news@\""
+                ),
+                pair(
+                    "sieve rule 3",
+                    "keep dropped, messages are moved without a copy"
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn comparator_takes_its_name() {
+        let (rules, reports) = import(
+            r#"if header :contains :comparator "i;octet" "subject" "Build" { fileinto "CI"; }
+            if header :comparator "i;ascii-casemap" :contains "subject" "ci" { fileinto "CI"; }"#,
+        );
+        assert_eq!(
+            rules,
+            vec![
+                pair("SUBJECT \"Build\"", "CI"),
+                pair("SUBJECT \"ci\"", "CI")
+            ]
+        );
+        assert_eq!(
+            reports,
+            vec![pair(
+                "sieve rule 1",
+                "header comparator i;octet searched case insensitively"
+            )]
+        );
+    }
+
+    #[test]
+    fn what_cannot_be_imported_is_reported() {
+        let (rules, reports) = import(
+            r#"if header :regex "subject" "^a" { fileinto "A"; }
+            if header :matches "subject" "a*b" { fileinto "B"; }
+            if header :contains "subject" "c" { redirect "x@y.z"; }
+            vacation "away";"#,
+        );
+        assert!(rules.is_empty());
+        assert_eq!(
+            reports,
+            vec![
+                pair("sieve rule 1", "not imported: regex match is not supported"),
+                pair(
+                    "sieve rule 2",
+                    "not imported: wildcards inside a*b are not supported"
+                ),
+                pair("sieve rule 3", "not imported: unsupported action redirect"),
+                pair("sieve rule 4", "not imported: unsupported command vacation")
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_scripts_fail() {
+        assert!(import_sieve("if header :contains \"subject\" \"a\" { fileinto \"A\";").is_err());
+        assert!(import_sieve("if header :contains \"subject\" \"a { }").is_err());
+        assert!(import_sieve("}").is_err());
+    }
+
+    #[test]
+    fn export_then_import_gives_the_same_rules() {
+        let mut flagged = rule("ci", "FROM ci@example.com", "CI");
+        flagged.flags = Some(vec!["\\Seen".to_string()]);
+        let rules = rules_set(vec![
+            rule(
+                "vendor",
+                "FROM vendor@example.com SUBJECT invoice",
+                "Vendors",
+            ),
+            rule("big", "OR LARGER 1000000 BODY \"attached\"", "Big"),
+            rule("not news", "NOT HEADER X-Mailer news", "Other"),
+            flagged,
+        ]);
+        let (script, unsupported) = export_sieve(&rules);
+        assert!(unsupported.is_empty());
+        let (imported, reports) = import_sieve(&script).unwrap();
+        assert!(reports.is_empty());
+        let names: Vec<&str> = imported.folders[0]
+            .rules
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["vendor", "big", "not news", "ci"]);
+        assert_eq!(
+            imported.folders[0].rules[3].flags,
+            Some(vec!["\\Seen".to_string()])
+        );
+        // the filters are quoted once imported, the script they give is the same
+        assert_eq!(export_sieve(&imported).0, script);
+    }
+
+    #[test]
+    fn rules_sieve_cannot_express_are_not_exported() {
+        let mut list = rule("list", "", "Lists/{list_id}");
+        list.list = Some("rust.example.com".to_string());
+        let rules = rules_set(vec![
+            rule("flagged", "FLAGGED", "Flagged"),
+            list,
+            rule("remote", "FROM a@b.c", "account:archive/Old"),
+        ]);
+        let (script, unsupported) = export_sieve(&rules);
+        assert!(!script.contains("fileinto"));
+        let names: Vec<&str> = unsupported.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["flagged", "list", "remote"]);
+    }
+}
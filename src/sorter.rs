@@ -0,0 +1,301 @@
+use crate::imap_tools::{apply_rules_on_folder, RunOptions};
+use crate::rules::{folder_matches, is_folder_pattern, RulesSet};
+use crate::store::MailStore;
+use crate::tags::TagExpression;
+use anyhow::Context;
+use serde::Serialize;
+use std::time::Instant;
+
+// what a rule did on a folder
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RuleReport {
+    pub rule: String,
+    pub target: String,
+    // messages found by the rule, once classified and expanded to their threads
+    pub matched: usize,
+    pub moved: usize,
+    // why matched messages were not moved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+    pub duration_ms: u64,
+    pub errors: Vec<String>,
+    // uids of the messages moved
+    pub uids: Vec<u32>,
+}
+
+// what the rules of a folder did on it
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FolderReport {
+    pub folder: String,
+    pub rules: Vec<RuleReport>,
+    pub duration_ms: u64,
+}
+
+// what a run did, folder by folder and rule by rule
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunReport {
+    pub started: String,
+    pub duration_ms: u64,
+    pub folders: Vec<FolderReport>,
+    #[serde(skip)]
+    start: Option<Instant>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+impl RunReport {
+    pub fn new() -> Self {
+        RunReport {
+            started: chrono::Local::now().to_rfc3339(),
+            start: Some(Instant::now()),
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, folder: FolderReport) {
+        self.folders.push(folder);
+    }
+
+    pub fn extend(&mut self, other: RunReport) {
+        self.folders.extend(other.folders);
+    }
+
+    // stop the clock of the run
+    pub fn finish(&mut self) {
+        if let Some(start) = self.start {
+            self.duration_ms = start.elapsed().as_millis() as u64;
+        }
+    }
+
+    pub fn moved(&self) -> usize {
+        self.rules().map(|rule| rule.moved).sum()
+    }
+
+    pub fn errors(&self) -> usize {
+        self.rules().map(|rule| rule.errors.len()).sum()
+    }
+
+    fn rules(&self) -> impl Iterator<Item = &RuleReport> {
+        self.folders.iter().flat_map(|folder| folder.rules.iter())
+    }
+
+    // one line per rule that matched something or failed
+    pub fn as_text(&self) -> String {
+        let mut text = format!(
+            "{folder:<30} {rule:<30} {matched:>8} {moved:>8} status\n",
+            folder = "folder",
+            rule = "rule",
+            matched = "matched",
+            moved = "moved"
+        );
+        for folder in &self.folders {
+            for rule in &folder.rules {
+                if rule.matched == 0 && rule.errors.is_empty() {
+                    continue;
+                }
+                let status = match (&rule.skipped, rule.errors.first()) {
+                    (_, Some(error)) => format!("error: {}", error),
+                    (Some(skipped), None) => skipped.clone(),
+                    (None, None) => "ok".to_string(),
+                };
+                text.push_str(&format!(
+                    "{folder:<30} {rule:<30} {matched:>8} {moved:>8} {status}\n",
+                    folder = folder.folder,
+                    rule = rule.rule,
+                    matched = rule.matched,
+                    moved = rule.moved,
+                    status = status
+                ));
+            }
+        }
+        text.push_str(&format!(
+            "{} messages moved, {} errors, in {} ms\n",
+            self.moved(),
+            self.errors(),
+            self.duration_ms
+        ));
+        text
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("report is serializable")
+    }
+
+    // write the report to a file, or print it when there is none
+    pub fn write(&self, format: ReportFormat, path: Option<&str>) -> anyhow::Result<()> {
+        let content = match format {
+            ReportFormat::Text => self.as_text(),
+            ReportFormat::Json => self.as_json(),
+        };
+        match path {
+            Some(path) => std::fs::write(path, content)
+                .with_context(|| format!("cannot write report to {}", path)),
+            None => {
+                println!("{}", content);
+                Ok(())
+            }
+        }
+    }
+}
+
+// which folders to sort, and with the rules of which folders
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum FolderSelection {
+    // every folder having rules, with its own rules
+    #[default]
+    All,
+    // these folders, or IMAP LIST patterns, with their own rules
+    Only(Vec<String>),
+    // the rules of each of these folders applied on each of those, both may be patterns
+    RulesOf {
+        rules_of: Vec<String>,
+        on: Vec<String>,
+    },
+}
+
+// folders matching names or patterns, in the order given, a folder matched twice is kept once
+fn matching_folders(specs: &[String], folders: &[String], delimiter: &str) -> Vec<String> {
+    let mut matched: Vec<String> = Vec::new();
+    for spec in specs {
+        let found: Vec<&String> = folders
+            .iter()
+            .filter(|folder| {
+                if is_folder_pattern(spec) {
+                    folder_matches(spec, folder, delimiter)
+                } else {
+                    folder.eq_ignore_ascii_case(spec)
+                }
+            })
+            .collect();
+        if found.is_empty() {
+            log::warn!("{} matches no folder", spec);
+        }
+        for folder in found {
+            if !matched.contains(folder) {
+                matched.push(folder.clone());
+            }
+        }
+    }
+    matched
+}
+
+impl FolderSelection {
+    // tell if the folders of the server are needed to resolve the selection
+    pub fn has_patterns(&self) -> bool {
+        match self {
+            FolderSelection::All => false,
+            FolderSelection::Only(folders) => folders.iter().any(|spec| is_folder_pattern(spec)),
+            FolderSelection::RulesOf { rules_of, on } => rules_of
+                .iter()
+                .chain(on.iter())
+                .any(|spec| is_folder_pattern(spec)),
+        }
+    }
+
+    // pairs of the folder whose rules apply and the folder they apply on,
+    // rules folders are taken among the folders having rules, the others among the mailboxes
+    pub fn resolve(
+        &self,
+        rules_set: &RulesSet,
+        mailboxes: &[String],
+        delimiter: &str,
+    ) -> Vec<(String, String)> {
+        let with_rules = rules_set.list_folders();
+        match self {
+            FolderSelection::All => with_rules
+                .into_iter()
+                .map(|folder| (folder.clone(), folder))
+                .collect(),
+            FolderSelection::Only(folders) => matching_folders(folders, &with_rules, delimiter)
+                .into_iter()
+                .map(|folder| (folder.clone(), folder))
+                .collect(),
+            FolderSelection::RulesOf { rules_of, on } => {
+                let rules_folders = matching_folders(rules_of, &with_rules, delimiter);
+                // a plain name is sorted even when the server was not listed
+                let mut folders: Vec<String> = Vec::new();
+                for spec in on {
+                    let found = if is_folder_pattern(spec) {
+                        matching_folders(std::slice::from_ref(spec), mailboxes, delimiter)
+                    } else {
+                        vec![spec.clone()]
+                    };
+                    for folder in found {
+                        if !folders.contains(&folder) {
+                            folders.push(folder);
+                        }
+                    }
+                }
+                rules_folders
+                    .iter()
+                    .flat_map(|rules_folder| {
+                        folders
+                            .iter()
+                            .map(move |folder| (rules_folder.clone(), folder.clone()))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+// applies the rules of a rules set on a mail store, the entry point to embed the sorting engine
+pub struct Sorter<'a> {
+    pub rules_set: &'a RulesSet,
+    pub options: &'a RunOptions<'a>,
+    // only rules selected by this tag expression
+    pub tag: Option<TagExpression>,
+}
+
+impl<'a> Sorter<'a> {
+    pub fn new(rules_set: &'a RulesSet, options: &'a RunOptions<'a>) -> Self {
+        Sorter {
+            rules_set,
+            options,
+            tag: None,
+        }
+    }
+
+    pub fn with_tag(mut self, tag: Option<TagExpression>) -> Self {
+        self.tag = tag;
+        self
+    }
+
+    // apply the rules of a folder on another folder
+    pub fn apply_rules_of(
+        &self,
+        store: &mut dyn MailStore,
+        rules_folder: &str,
+        folder: &str,
+    ) -> FolderReport {
+        let rules = self.rules_set.rules_for_folder(&rules_folder.to_string());
+        apply_rules_on_folder(store, rules, &folder.to_string(), &self.tag, self.options)
+    }
+
+    // apply the rules of a folder on itself
+    pub fn sort_folder(&self, store: &mut dyn MailStore, folder: &str) -> FolderReport {
+        self.apply_rules_of(store, folder, folder)
+    }
+
+    // sort every folder having rules
+    pub fn run(&self, store: &mut dyn MailStore) -> RunReport {
+        self.run_on(
+            store,
+            &FolderSelection::All.resolve(self.rules_set, &[], ""),
+        )
+    }
+
+    // apply the rules of each pair's first folder on its second one, as resolved by a FolderSelection
+    pub fn run_on(&self, store: &mut dyn MailStore, folders: &[(String, String)]) -> RunReport {
+        let mut report = RunReport::new();
+        for (rules_folder, folder) in folders {
+            report.push(self.apply_rules_of(store, rules_folder, folder));
+        }
+        report.finish();
+        report
+    }
+}
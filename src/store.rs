@@ -0,0 +1,195 @@
+use crate::imap_tools::{delete_uids, uid_move_or_create, uid_set};
+use crate::thread::parse_thread_response;
+use chrono::{DateTime, FixedOffset};
+use imap::ImapConnection;
+
+// what the sorting engine needs from a mail server, so that it can be embedded on other stores
+// than an IMAP session, all uids are those of the opened folder
+
+// one whole message, with its flags and date
+#[derive(Debug, Clone, Default)]
+pub struct StoredMessage {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub internal_date: Option<DateTime<FixedOffset>>,
+    pub body: Vec<u8>,
+}
+
+// uid, header fields and start of the text of a message
+pub type HeaderAndText = (u32, Vec<u8>, Vec<u8>);
+
+pub trait MailStore {
+    // open a folder for the following operations
+    fn open_folder(&mut self, folder: &str) -> anyhow::Result<()>;
+
+    // uids matching an IMAP search query, sorted
+    fn search_uids(&mut self, query: &str) -> anyhow::Result<Vec<u32>>;
+
+    // header fields of messages, as raw header blocks
+    fn fetch_headers(
+        &mut self,
+        uids: &[u32],
+        fields: &[&str],
+    ) -> anyhow::Result<Vec<(u32, Vec<u8>)>>;
+
+    // header fields and the start of the text of messages
+    fn fetch_headers_and_text(
+        &mut self,
+        uids: &[u32],
+        fields: &[&str],
+        text_limit: usize,
+    ) -> anyhow::Result<Vec<HeaderAndText>>;
+
+    // internal dates of messages, when the server received them
+    fn fetch_dates(&mut self, uids: &[u32]) -> anyhow::Result<Vec<(u32, DateTime<FixedOffset>)>>;
+
+    // whole messages, without marking them as seen
+    fn fetch_messages(&mut self, uids: &[u32]) -> anyhow::Result<Vec<StoredMessage>>;
+
+    fn add_flags(&mut self, uids: &[u32], flags: &[String]) -> anyhow::Result<()>;
+
+    // move messages to another folder, created when missing
+    fn move_messages(&mut self, uids: &[u32], target: &str) -> anyhow::Result<()>;
+
+    fn delete_messages(&mut self, uids: &[u32]) -> anyhow::Result<()>;
+
+    // tell if messages can be deleted without touching other deleted messages of the folder
+    fn can_delete(&mut self) -> anyhow::Result<bool>;
+
+    // threads of the opened folder when the store computes them, None otherwise
+    fn threads(&mut self) -> anyhow::Result<Option<Vec<Vec<u32>>>>;
+}
+
+impl MailStore for imap::Session<Box<dyn ImapConnection>> {
+    fn open_folder(&mut self, folder: &str) -> anyhow::Result<()> {
+        self.select(folder)?;
+        Ok(())
+    }
+
+    fn search_uids(&mut self, query: &str) -> anyhow::Result<Vec<u32>> {
+        let mut uids: Vec<u32> = self.uid_search(query)?.into_iter().collect();
+        uids.sort();
+        Ok(uids)
+    }
+
+    fn fetch_headers(
+        &mut self,
+        uids: &[u32],
+        fields: &[&str],
+    ) -> anyhow::Result<Vec<(u32, Vec<u8>)>> {
+        if uids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let query = format!(
+            "BODY.PEEK[HEADER.FIELDS ({})]",
+            fields.join(" ").to_uppercase()
+        );
+        let messages = self.uid_fetch(uid_set(uids), query)?;
+        Ok(messages
+            .iter()
+            .filter_map(|message| {
+                Some((message.uid?, message.header().unwrap_or_default().to_vec()))
+            })
+            .collect())
+    }
+
+    fn fetch_headers_and_text(
+        &mut self,
+        uids: &[u32],
+        fields: &[&str],
+        text_limit: usize,
+    ) -> anyhow::Result<Vec<HeaderAndText>> {
+        if uids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let query = format!(
+            "(BODY.PEEK[HEADER.FIELDS ({})] BODY.PEEK[TEXT]<0.{}>)",
+            fields.join(" ").to_uppercase(),
+            text_limit
+        );
+        let messages = self.uid_fetch(uid_set(uids), query)?;
+        Ok(messages
+            .iter()
+            .filter_map(|message| {
+                Some((
+                    message.uid?,
+                    message.header().unwrap_or_default().to_vec(),
+                    message.text().unwrap_or_default().to_vec(),
+                ))
+            })
+            .collect())
+    }
+
+    fn fetch_dates(&mut self, uids: &[u32]) -> anyhow::Result<Vec<(u32, DateTime<FixedOffset>)>> {
+        if uids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let messages = self.uid_fetch(uid_set(uids), "INTERNALDATE")?;
+        Ok(messages
+            .iter()
+            .filter_map(|message| Some((message.uid?, message.internal_date()?)))
+            .collect())
+    }
+
+    fn fetch_messages(&mut self, uids: &[u32]) -> anyhow::Result<Vec<StoredMessage>> {
+        if uids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let messages = self.uid_fetch(uid_set(uids), "(UID FLAGS INTERNALDATE BODY.PEEK[])")?;
+        Ok(messages
+            .iter()
+            .filter_map(|message| {
+                Some(StoredMessage {
+                    uid: message.uid?,
+                    flags: message
+                        .flags()
+                        .iter()
+                        .filter(|flag| **flag != imap::types::Flag::Recent)
+                        .map(|flag| flag.to_string())
+                        .collect(),
+                    internal_date: message.internal_date(),
+                    body: message.body()?.to_vec(),
+                })
+            })
+            .collect())
+    }
+
+    fn add_flags(&mut self, uids: &[u32], flags: &[String]) -> anyhow::Result<()> {
+        if uids.is_empty() || flags.is_empty() {
+            return Ok(());
+        }
+        self.uid_store(
+            uid_set(uids),
+            format!("+FLAGS.SILENT ({})", flags.join(" ")),
+        )?;
+        Ok(())
+    }
+
+    fn move_messages(&mut self, uids: &[u32], target: &str) -> anyhow::Result<()> {
+        if uids.is_empty() {
+            return Ok(());
+        }
+        uid_move_or_create(self, &uid_set(uids), target)?;
+        Ok(())
+    }
+
+    fn delete_messages(&mut self, uids: &[u32]) -> anyhow::Result<()> {
+        if uids.is_empty() {
+            return Ok(());
+        }
+        delete_uids(self, uids)?;
+        Ok(())
+    }
+
+    fn can_delete(&mut self) -> anyhow::Result<bool> {
+        Ok(self.capabilities()?.has_str("UIDPLUS"))
+    }
+
+    fn threads(&mut self) -> anyhow::Result<Option<Vec<Vec<u32>>>> {
+        if !self.capabilities()?.has_str("THREAD=REFERENCES") {
+            return Ok(None);
+        }
+        let response = self.run_command_and_read_response("UID THREAD REFERENCES UTF-8 ALL")?;
+        Ok(Some(parse_thread_response(&response)))
+    }
+}
@@ -0,0 +1,181 @@
+use crate::imap_tools::{address, decode_header, header_value};
+use crate::lists::list_id;
+use crate::rules::Rule;
+use crate::store::MailStore;
+use std::collections::HashMap;
+
+// a cluster needs at least this many messages to be suggested
+const MIN_COUNT: usize = 3;
+// number of suggestions printed for each kind of cluster
+const MAX_SUGGESTIONS: usize = 10;
+// number of example subjects shown for each suggestion
+const MAX_EXAMPLES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ClusterKind {
+    List,
+    Sender,
+    Domain,
+    Subject,
+}
+
+#[derive(Debug)]
+pub struct Cluster {
+    pub kind: ClusterKind,
+    pub value: String,
+    pub count: usize,
+    pub examples: Vec<String>,
+}
+
+impl Cluster {
+    // candidate rule for this cluster, disabled until reviewed
+    pub fn to_rule(&self) -> Rule {
+        let (name, filter, list, target) = match self.kind {
+            ClusterKind::List => (
+                format!("list {}", self.value),
+                String::new(),
+                Some(self.value.clone()),
+                "Lists/{list_id}".to_string(),
+            ),
+            ClusterKind::Sender => (
+                format!("from {}", self.value),
+                format!("FROM {}", self.value),
+                None,
+                format!("Senders/{}", self.value),
+            ),
+            ClusterKind::Domain => (
+                format!("domain {}", self.value),
+                format!("FROM @{}", self.value),
+                None,
+                format!("Senders/{}", self.value),
+            ),
+            ClusterKind::Subject => (
+                format!("subject {}", self.value),
+                format!("SUBJECT \"{}\"", self.value.replace('"', "")),
+                None,
+                self.value
+                    .trim_matches(|c: char| c == '[' || c == ']' || c == ':')
+                    .to_string(),
+            ),
+        };
+        Rule {
+            name,
+            filter,
+            list,
+            target,
+            enable: false,
+            ..Default::default()
+        }
+    }
+}
+
+// common beginning of a subject: a [tag], or what comes before a colon, or the first two words
+pub(crate) fn subject_prefix(subject: &str) -> Option<String> {
+    let mut subject = subject.trim();
+    // replies and forwards belong to the original subject
+    loop {
+        let lower = subject.to_lowercase();
+        let Some(stripped) = ["re:", "fw:", "fwd:", "tr:"]
+            .iter()
+            .find(|prefix| lower.starts_with(*prefix))
+            .map(|prefix| subject[prefix.len()..].trim_start())
+        else {
+            break;
+        };
+        subject = stripped;
+    }
+    if subject.starts_with('[') {
+        if let Some(end) = subject.find(']') {
+            return Some(subject[..=end].to_string());
+        }
+    }
+    if let Some((prefix, _)) = subject.split_once(':') {
+        if !prefix.is_empty() && prefix.split_whitespace().count() <= 3 {
+            return Some(format!("{}:", prefix));
+        }
+    }
+    let words: Vec<&str> = subject.split_whitespace().take(2).collect();
+    if words.len() < 2 {
+        return None;
+    }
+    Some(words.join(" "))
+}
+
+// group messages of a folder by mailing list, sender, sender domain and subject prefix
+pub fn find_clusters(store: &mut dyn MailStore, folder: &str) -> anyhow::Result<Vec<Cluster>> {
+    store.open_folder(folder)?;
+    let uids = store.search_uids("ALL")?;
+    let headers = store.fetch_headers(&uids, &["From", "Subject", "List-Id"])?;
+
+    let mut clusters: HashMap<(ClusterKind, String), Cluster> = HashMap::new();
+    let mut add = |kind: ClusterKind, value: String, subject: &str| {
+        let cluster = clusters.entry((kind, value.clone())).or_insert(Cluster {
+            kind,
+            value,
+            count: 0,
+            examples: Vec::new(),
+        });
+        cluster.count += 1;
+        if cluster.examples.len() < MAX_EXAMPLES && !cluster.examples.iter().any(|s| s == subject) {
+            cluster.examples.push(subject.to_string());
+        }
+    };
+
+    for (_, header) in headers {
+        let subject = header_value(&header, "Subject")
+            .map(|subject| decode_header(subject.as_bytes()))
+            .unwrap_or_default();
+
+        if let Some(list) = header_value(&header, "List-Id") {
+            add(ClusterKind::List, list_id(&list), &subject);
+        }
+        let sender = header_value(&header, "From")
+            .map(|from| address(&from))
+            .unwrap_or_default();
+        if let Some((_, host)) = sender.split_once('@') {
+            add(ClusterKind::Sender, sender.clone(), &subject);
+            add(ClusterKind::Domain, host.to_string(), &subject);
+        }
+        if let Some(prefix) = subject_prefix(&subject) {
+            add(ClusterKind::Subject, prefix, &subject);
+        }
+    }
+
+    let mut clusters: Vec<Cluster> = clusters
+        .into_values()
+        .filter(|cluster| cluster.count >= MIN_COUNT)
+        .collect();
+    clusters.sort_by(|a, b| {
+        a.kind
+            .cmp(&b.kind)
+            .then(b.count.cmp(&a.count))
+            .then(a.value.cmp(&b.value))
+    });
+    Ok(clusters)
+}
+
+// print candidate rules as YAML, ready to paste in a rules file
+pub fn print_suggestions(folder: &str, clusters: &[Cluster]) {
+    println!(
+        "# rules suggested from messages of {}, disabled until reviewed",
+        folder
+    );
+    println!("rules:");
+    let mut printed: HashMap<ClusterKind, usize> = HashMap::new();
+    for cluster in clusters {
+        let count = printed.entry(cluster.kind).or_default();
+        if *count >= MAX_SUGGESTIONS {
+            continue;
+        }
+        *count += 1;
+        println!();
+        println!("  # {} messages, for instance:", cluster.count);
+        for example in &cluster.examples {
+            println!("  #   {}", example);
+        }
+        let yaml = serde_yaml::to_string(&vec![cluster.to_rule()]).unwrap_or_default();
+        for line in yaml.lines() {
+            println!("  {}", line);
+        }
+    }
+}
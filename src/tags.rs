@@ -0,0 +1,238 @@
+use std::fmt;
+use std::str::FromStr;
+
+// boolean expressions selecting rules by their tags, like `daily & !slow` or `newsletter | promo`
+// `!` binds tighter than `&`, which binds tighter than `|`, parentheses group
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagExpression {
+    Tag(String),
+    Not(Box<TagExpression>),
+    And(Box<TagExpression>, Box<TagExpression>),
+    Or(Box<TagExpression>, Box<TagExpression>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Tag(String),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Tag(tag) => write!(f, "{}", tag),
+            Token::Not => write!(f, "!"),
+            Token::And => write!(f, "&"),
+            Token::Or => write!(f, "|"),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+        }
+    }
+}
+
+// tags are words, with - _ . : and / allowed within
+fn is_tag_char(c: char) -> bool {
+    c.is_alphanumeric() || "-_.:/".contains(c)
+}
+
+fn tokenize(text: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '!' => {
+                chars.next();
+                tokens.push(Token::Not);
+            }
+            '&' => {
+                chars.next();
+                tokens.push(Token::And);
+            }
+            '|' => {
+                chars.next();
+                tokens.push(Token::Or);
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            c if is_tag_char(c) => {
+                let mut tag = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !is_tag_char(c) {
+                        break;
+                    }
+                    tag.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Tag(tag));
+            }
+            c => anyhow::bail!("invalid character {} in tag expression", c),
+        }
+    }
+    Ok(tokens)
+}
+
+// recursive descent over the tokens, one function per precedence level
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self) -> anyhow::Result<TagExpression> {
+        let mut expression = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expression = TagExpression::Or(Box::new(expression), Box::new(self.and()?));
+        }
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> anyhow::Result<TagExpression> {
+        let mut expression = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expression = TagExpression::And(Box::new(expression), Box::new(self.not()?));
+        }
+        Ok(expression)
+    }
+
+    fn not(&mut self) -> anyhow::Result<TagExpression> {
+        match self.next() {
+            Some(Token::Not) => Ok(TagExpression::Not(Box::new(self.not()?))),
+            Some(Token::Tag(tag)) => Ok(TagExpression::Tag(tag)),
+            Some(Token::Open) => {
+                let expression = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expression),
+                    _ => anyhow::bail!("missing ) in tag expression"),
+                }
+            }
+            Some(token) => anyhow::bail!("unexpected {} in tag expression", token),
+            None => anyhow::bail!("tag expression ends too early"),
+        }
+    }
+}
+
+impl TagExpression {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            position: 0,
+        };
+        let expression = parser.or()?;
+        if let Some(token) = parser.peek() {
+            anyhow::bail!("unexpected {} in tag expression", token);
+        }
+        Ok(expression)
+    }
+
+    // tell if a rule with these tags is selected
+    pub fn matches(&self, tags: &[String]) -> bool {
+        match self {
+            TagExpression::Tag(tag) => tags.contains(tag),
+            TagExpression::Not(expression) => !expression.matches(tags),
+            TagExpression::And(left, right) => left.matches(tags) && right.matches(tags),
+            TagExpression::Or(left, right) => left.matches(tags) || right.matches(tags),
+        }
+    }
+}
+
+impl FromStr for TagExpression {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        TagExpression::parse(text)
+    }
+}
+
+// written back with parentheses around each operation, so that it reads without precedence rules
+impl fmt::Display for TagExpression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TagExpression::Tag(tag) => write!(f, "{}", tag),
+            TagExpression::Not(expression) => write!(f, "!{}", expression),
+            TagExpression::And(left, right) => write!(f, "({} & {})", left, right),
+            TagExpression::Or(left, right) => write!(f, "({} | {})", left, right),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        let expression = TagExpression::parse("daily & !slow").unwrap();
+        assert_eq!(expression.to_string(), "(daily & !slow)");
+        assert!(expression.matches(&tags(&["daily"])));
+        assert!(expression.matches(&tags(&["daily", "fast"])));
+        assert!(!expression.matches(&tags(&["daily", "slow"])));
+        assert!(!expression.matches(&tags(&["weekly"])));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let expression = TagExpression::parse("a | b & c").unwrap();
+        assert_eq!(expression.to_string(), "(a | (b & c))");
+        assert!(expression.matches(&tags(&["a"])));
+        assert!(expression.matches(&tags(&["b", "c"])));
+        assert!(!expression.matches(&tags(&["b"])));
+        assert!(!expression.matches(&tags(&[])));
+    }
+
+    #[test]
+    fn parentheses_group() {
+        let expression = TagExpression::parse("(daily | weekly) & !slow").unwrap();
+        assert_eq!(expression.to_string(), "((daily | weekly) & !slow)");
+        assert!(expression.matches(&tags(&["weekly"])));
+        assert!(!expression.matches(&tags(&["weekly", "slow"])));
+    }
+
+    #[test]
+    fn unbalanced_parentheses_are_errors() {
+        for text in ["(a | b", "a | b)", "((a)", "()", "a & (b | c))"] {
+            assert!(TagExpression::parse(text).is_err(), "{} parsed", text);
+        }
+    }
+
+    #[test]
+    fn invalid_characters_are_errors() {
+        for text in ["a ^ b", "a && b", "daily, weekly", "\"news\""] {
+            assert!(TagExpression::parse(text).is_err(), "{} parsed", text);
+        }
+        assert_eq!(
+            TagExpression::parse("a ^ b").unwrap_err().to_string(),
+            "invalid character ^ in tag expression"
+        );
+        assert!(TagExpression::parse("mailing-list | news_2024 | v1.2").is_ok());
+    }
+}
@@ -0,0 +1,265 @@
+use crate::imap_tools::{header_value, uid_set};
+use crate::store::MailStore;
+use std::collections::{HashMap, HashSet};
+
+// parse the untagged THREAD response (rfc5256) into a list of threads, each one a list of uids
+pub fn parse_thread_response(response: &[u8]) -> Vec<Vec<u32>> {
+    let response = String::from_utf8_lossy(response);
+    let mut threads = Vec::new();
+    for line in response.lines() {
+        let Some(list) = line.strip_prefix("* THREAD") else {
+            continue;
+        };
+        let mut depth = 0;
+        let mut current: Vec<u32> = Vec::new();
+        let mut number = String::new();
+        for c in list.chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+            if !number.is_empty() {
+                current.extend(number.parse::<u32>().ok());
+                number.clear();
+            }
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    // closing a top level parenthesis ends a thread
+                    if depth == 0 && !current.is_empty() {
+                        threads.push(std::mem::take(&mut current));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    threads
+}
+
+// message ids referenced by a message, from In-Reply-To and References
+fn referenced_ids(header: &[u8]) -> Vec<String> {
+    ["In-Reply-To", "References"]
+        .iter()
+        .filter_map(|field| header_value(header, field))
+        .flat_map(|value| {
+            value
+                .split('>')
+                .filter_map(|id| id.split_once('<').map(|(_, id)| format!("<{}>", id.trim())))
+                .collect::<Vec<String>>()
+        })
+        .collect()
+}
+
+// threads of the selected folder, computed from Message-ID, In-Reply-To and References
+fn client_threads(store: &mut dyn MailStore) -> anyhow::Result<Vec<Vec<u32>>> {
+    let uids = store.search_uids("ALL")?;
+    let messages = store.fetch_headers(&uids, &["Message-ID", "In-Reply-To", "References"])?;
+
+    // union find over message ids, each message joins the ids it references
+    let mut parent: HashMap<String, String> = HashMap::new();
+    fn find(parent: &mut HashMap<String, String>, id: &str) -> String {
+        let mut root = id.to_string();
+        while let Some(next) = parent.get(&root) {
+            if next == &root {
+                break;
+            }
+            root = next.clone();
+        }
+        parent.insert(id.to_string(), root.clone());
+        root
+    }
+
+    let mut message_ids: Vec<(u32, String)> = Vec::new();
+    for (uid, header) in messages {
+        let header = header.as_slice();
+        // a message without Message-ID is a thread on its own
+        let message_id =
+            header_value(header, "Message-ID").unwrap_or_else(|| format!("uid:{}", uid));
+        let root = find(&mut parent, &message_id);
+        for referenced in referenced_ids(header) {
+            let other = find(&mut parent, &referenced);
+            if other != root {
+                parent.insert(other, root.clone());
+            }
+        }
+        message_ids.push((uid, message_id));
+    }
+
+    let mut threads: HashMap<String, Vec<u32>> = HashMap::new();
+    for (uid, message_id) in message_ids {
+        let root = find(&mut parent, &message_id);
+        threads.entry(root).or_default().push(uid);
+    }
+    Ok(threads.into_values().collect())
+}
+
+// expand a set of uids of the selected folder to all messages of their threads
+pub fn expand_to_threads(store: &mut dyn MailStore, uids: &[u32]) -> anyhow::Result<Vec<u32>> {
+    let threads = match store.threads()? {
+        Some(threads) => threads,
+        None => {
+            log::debug!("server cannot thread, threading from message headers");
+            client_threads(store)?
+        }
+    };
+
+    let matched: HashSet<u32> = uids.iter().copied().collect();
+    let mut expanded: HashSet<u32> = matched.clone();
+    for thread in threads {
+        if thread.iter().any(|uid| matched.contains(uid)) {
+            expanded.extend(thread);
+        }
+    }
+    let mut expanded: Vec<u32> = expanded.into_iter().collect();
+    expanded.sort();
+    log::debug!(
+        "thread     : {} messages expanded to {} ({})",
+        uids.len(),
+        expanded.len(),
+        uid_set(&expanded)
+    );
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{HeaderAndText, StoredMessage};
+    use chrono::{DateTime, FixedOffset};
+
+    // a folder of messages known by their header only, on a store that cannot thread
+    struct Headers(Vec<(u32, &'static str)>);
+
+    impl MailStore for Headers {
+        fn open_folder(&mut self, _folder: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn search_uids(&mut self, _query: &str) -> anyhow::Result<Vec<u32>> {
+            Ok(self.0.iter().map(|(uid, _)| *uid).collect())
+        }
+
+        fn fetch_headers(
+            &mut self,
+            uids: &[u32],
+            _fields: &[&str],
+        ) -> anyhow::Result<Vec<(u32, Vec<u8>)>> {
+            Ok(self
+                .0
+                .iter()
+                .filter(|(uid, _)| uids.contains(uid))
+                .map(|(uid, header)| (*uid, header.replace('\n', "\r\n").into_bytes()))
+                .collect())
+        }
+
+        fn fetch_headers_and_text(
+            &mut self,
+            _uids: &[u32],
+            _fields: &[&str],
+            _text_limit: usize,
+        ) -> anyhow::Result<Vec<HeaderAndText>> {
+            unreachable!()
+        }
+
+        fn fetch_dates(
+            &mut self,
+            _uids: &[u32],
+        ) -> anyhow::Result<Vec<(u32, DateTime<FixedOffset>)>> {
+            unreachable!()
+        }
+
+        fn fetch_messages(&mut self, _uids: &[u32]) -> anyhow::Result<Vec<StoredMessage>> {
+            unreachable!()
+        }
+
+        fn add_flags(&mut self, _uids: &[u32], _flags: &[String]) -> anyhow::Result<()> {
+            unreachable!()
+        }
+
+        fn move_messages(&mut self, _uids: &[u32], _target: &str) -> anyhow::Result<()> {
+            unreachable!()
+        }
+
+        fn delete_messages(&mut self, _uids: &[u32]) -> anyhow::Result<()> {
+            unreachable!()
+        }
+
+        fn can_delete(&mut self) -> anyhow::Result<bool> {
+            unreachable!()
+        }
+
+        fn threads(&mut self) -> anyhow::Result<Option<Vec<Vec<u32>>>> {
+            Ok(None)
+        }
+    }
+
+    fn conversation() -> Headers {
+        Headers(vec![
+            (1, "Message-ID: <a@x>\n\n"),
+            (2, "Message-ID: <b@x>\nIn-Reply-To: <a@x>\n\n"),
+            (3, "Message-ID: <c@x>\nReferences: <a@x>\n <b@x>\n\n"),
+            (4, "Message-ID: <d@x>\n\n"),
+            // without Message-ID, a thread on its own
+            (5, "Subject: hello\n\n"),
+            // both answer a message that is not in the folder
+            (6, "Message-ID: <f@x>\nReferences: <gone@x>\n\n"),
+            (7, "Message-ID: <g@x>\nIn-Reply-To: <gone@x>\n\n"),
+            // an answer found before the message it answers
+            (8, "Message-ID: <e@x>\nIn-Reply-To: <h@x>\n\n"),
+            (9, "Message-ID: <h@x>\n\n"),
+        ])
+    }
+
+    #[test]
+    fn thread_responses() {
+        let cases: [(&[u8], Vec<Vec<u32>>); 5] = [
+            (b"* THREAD\r\n", vec![]),
+            (b"* THREAD (1)(2 3)\r\n", vec![vec![1], vec![2, 3]]),
+            // the example of rfc5256, with branches nested in a thread
+            (
+                b"* THREAD (2)(3 6 (4 23)(44 7 96))\r\n",
+                vec![vec![2], vec![3, 6, 4, 23, 44, 7, 96]],
+            ),
+            // a thread whose first message is missing
+            (b"* THREAD ((1)(2 (3)(4)))\r\n", vec![vec![1, 2, 3, 4]]),
+            // other lines are ignored
+            (
+                b"* OK still here\r\n* THREAD (10 11)\r\nA1 OK done\r\n",
+                vec![vec![10, 11]],
+            ),
+        ];
+        for (response, threads) in cases {
+            assert_eq!(
+                parse_thread_response(response),
+                threads,
+                "{}",
+                String::from_utf8_lossy(response)
+            );
+        }
+    }
+
+    #[test]
+    fn threads_from_references_and_in_reply_to() {
+        let mut threads = client_threads(&mut conversation()).unwrap();
+        for thread in &mut threads {
+            thread.sort();
+        }
+        threads.sort();
+        assert_eq!(
+            threads,
+            vec![vec![1, 2, 3], vec![4], vec![5], vec![6, 7], vec![8, 9]]
+        );
+    }
+
+    #[test]
+    fn uids_expand_to_their_threads() {
+        let mut store = conversation();
+        assert_eq!(
+            expand_to_threads(&mut store, &[2, 7]).unwrap(),
+            vec![1, 2, 3, 6, 7]
+        );
+        assert_eq!(expand_to_threads(&mut store, &[5]).unwrap(), vec![5]);
+    }
+}
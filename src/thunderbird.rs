@@ -0,0 +1,474 @@
+use crate::rules::{FolderRule, Rule, RulesSet};
+use crate::sieve::{or_chain, quoted};
+use anyhow::{Context, Result};
+
+// import of Thunderbird message filters (msgFilterRules.dat)
+
+#[derive(Debug, Default)]
+struct Filter {
+    name: String,
+    enabled: bool,
+    // actions with their value, in file order
+    actions: Vec<(String, String)>,
+    condition: String,
+}
+
+// unescape a value of the file, where quotes and backslashes are escaped
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+// filters of the file, each starting with its name line
+fn parse_filters(content: &str) -> Vec<Filter> {
+    let mut filters: Vec<Filter> = Vec::new();
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        // only the enclosing quotes, an escaped quote may end the value
+        let value = value.trim();
+        let value = unescape(
+            value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value),
+        );
+        if key == "name" {
+            filters.push(Filter {
+                name: value,
+                ..Default::default()
+            });
+            continue;
+        }
+        // version and logging come before the first filter
+        let Some(filter) = filters.last_mut() else {
+            continue;
+        };
+        match key {
+            "enabled" => filter.enabled = value == "yes",
+            "action" => filter.actions.push((value, String::new())),
+            "actionValue" => {
+                if let Some(action) = filter.actions.last_mut() {
+                    action.1 = value;
+                }
+            }
+            "condition" => filter.condition = value,
+            _ => {}
+        }
+    }
+    filters
+}
+
+#[derive(Debug, PartialEq)]
+struct Term {
+    or: bool,
+    attribute: String,
+    // the attribute was quoted, as custom headers are
+    quoted: bool,
+    operator: String,
+    value: String,
+}
+
+// one field of a term and whether it was quoted, as when it contains commas or parenthesis
+fn parse_field(chars: &mut std::iter::Peekable<std::str::Chars>) -> (String, bool) {
+    let mut field = String::new();
+    if chars.peek() == Some(&'"') {
+        chars.next();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => field.extend(chars.next()),
+                '"' => break,
+                c => field.push(c),
+            }
+        }
+        return (field, true);
+    }
+    while let Some(&c) = chars.peek() {
+        if c == ',' || c == ')' {
+            break;
+        }
+        field.push(c);
+        chars.next();
+    }
+    (field, false)
+}
+
+// terms of a condition like: AND (from,contains,a@b.com) AND (subject,contains,"x, y")
+fn parse_condition(condition: &str) -> Result<Vec<Term>, String> {
+    let mut terms = Vec::new();
+    let mut chars = condition.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let mut keyword = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '(' || c.is_whitespace() {
+                break;
+            }
+            keyword.push(c);
+            chars.next();
+        }
+        match keyword.as_str() {
+            "" if chars.peek().is_none() => return Ok(terms),
+            "ALL" => {
+                terms.push(Term {
+                    or: false,
+                    attribute: "all".to_string(),
+                    quoted: false,
+                    operator: String::new(),
+                    value: String::new(),
+                });
+                continue;
+            }
+            "AND" | "OR" => {}
+            other => return Err(format!("unexpected {} in condition", other)),
+        }
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.next() != Some('(') {
+            return Err("missing ( in condition".to_string());
+        }
+        let (attribute, quoted) = parse_field(&mut chars);
+        chars.next();
+        let (operator, _) = parse_field(&mut chars);
+        chars.next();
+        let (value, _) = parse_field(&mut chars);
+        if chars.next() != Some(')') {
+            return Err("missing ) in condition".to_string());
+        }
+        terms.push(Term {
+            or: keyword == "OR",
+            attribute: attribute.to_lowercase(),
+            quoted,
+            operator,
+            value,
+        });
+    }
+}
+
+// search keys matching a text attribute, notes about approximations are appended
+fn text_keys(term: &Term, header: &str, notes: &mut Vec<String>) -> Result<Vec<String>, String> {
+    let key = match header {
+        "from" | "to" | "cc" | "bcc" | "subject" | "body" => {
+            format!("{} {}", header.to_uppercase(), quoted(&term.value))
+        }
+        _ => format!("HEADER {} {}", header, quoted(&term.value)),
+    };
+    match term.operator.as_str() {
+        "contains" => Ok(vec![key]),
+        "doesn't contain" => Ok(vec![format!("NOT {}", key)]),
+        "is" | "begins with" | "ends with" => {
+            notes.push(format!("{} {} searched as contains", header, term.operator));
+            Ok(vec![key])
+        }
+        "isn't" => {
+            notes.push(format!("{} isn't searched as doesn't contain", header));
+            Ok(vec![format!("NOT {}", key)])
+        }
+        operator => Err(format!("unsupported operator {} on {}", operator, header)),
+    }
+}
+
+fn number(term: &Term) -> Result<u64, String> {
+    term.value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid number {} for {}", term.value, term.attribute))
+}
+
+// IMAP search keys equivalent to one term of a Thunderbird condition
+fn term_to_search(term: &Term, notes: &mut Vec<String>) -> Result<Vec<String>, String> {
+    let attribute = term.attribute.as_str();
+    match attribute {
+        "all" => Ok(vec!["ALL".to_string()]),
+        "from" | "to" | "cc" | "bcc" | "subject" | "body" => text_keys(term, attribute, notes),
+        "to or cc" => Ok(or_chain(vec![
+            text_keys(term, "to", notes)?,
+            text_keys(term, "cc", notes)?,
+        ])),
+        "age in days" => {
+            // relative dates need the WITHIN extension (rfc5032)
+            let seconds = number(term)? * 24 * 3600;
+            notes.push("age needs a server with the WITHIN extension".to_string());
+            match term.operator.as_str() {
+                "is greater than" => Ok(vec![format!("OLDER {}", seconds)]),
+                "is less than" => Ok(vec![format!("YOUNGER {}", seconds)]),
+                operator => Err(format!("unsupported operator {} on age", operator)),
+            }
+        }
+        "date" => match term.operator.as_str() {
+            "is before" => Ok(vec![format!("SENTBEFORE {}", term.value)]),
+            "is after" => Ok(vec![format!("SENTSINCE {}", term.value)]),
+            "is" => Ok(vec![format!("SENTON {}", term.value)]),
+            operator => Err(format!("unsupported operator {} on date", operator)),
+        },
+        "size" => {
+            // sizes are in KB
+            let bytes = number(term)? * 1024;
+            match term.operator.as_str() {
+                "is greater than" => Ok(vec![format!("LARGER {}", bytes)]),
+                "is less than" => Ok(vec![format!("SMALLER {}", bytes)]),
+                operator => Err(format!("unsupported operator {} on size", operator)),
+            }
+        }
+        "status" => {
+            let key = match term.value.to_lowercase().as_str() {
+                "read" => "SEEN",
+                "flagged" | "starred" => "FLAGGED",
+                "replied" => "ANSWERED",
+                "new" => "NEW",
+                value => return Err(format!("unsupported status {}", value)),
+            };
+            match term.operator.as_str() {
+                "is" => Ok(vec![key.to_string()]),
+                "isn't" => Ok(vec![format!("NOT {}", key)]),
+                operator => Err(format!("unsupported operator {} on status", operator)),
+            }
+        }
+        // tags are IMAP keywords
+        "tag" => match term.operator.as_str() {
+            "contains" | "is" => Ok(vec![format!("KEYWORD {}", term.value)]),
+            "doesn't contain" | "isn't" => Ok(vec![format!("UNKEYWORD {}", term.value)]),
+            operator => Err(format!("unsupported operator {} on tag", operator)),
+        },
+        // custom headers are written with their name quoted
+        header if term.quoted || header.contains('-') => text_keys(term, header, notes),
+        attribute => Err(format!("unsupported condition on {}", attribute)),
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = value.get(i + 1..i + 3).unwrap_or("");
+        if bytes[i] == b'%' {
+            if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+// folder name of an imap:// folder URI, local folders cannot be reached over IMAP
+fn folder_from_uri(uri: &str) -> Result<String, String> {
+    let Some(rest) = uri.strip_prefix("imap://") else {
+        return Err(format!("folder {} is not on an IMAP server", uri));
+    };
+    match rest.split_once('/') {
+        Some((_, folder)) if !folder.is_empty() => Ok(percent_decode(folder)),
+        _ => Err(format!("no folder in {}", uri)),
+    }
+}
+
+// rule equivalent to a filter, with notes about what changed on the way
+fn filter_to_rule(filter: &Filter, notes: &mut Vec<String>) -> Result<Rule, String> {
+    let terms = parse_condition(&filter.condition)?;
+    if terms.is_empty() {
+        return Err("no condition".to_string());
+    }
+    let keys = terms
+        .iter()
+        .map(|term| term_to_search(term, notes))
+        .collect::<Result<Vec<Vec<String>>, String>>()?;
+    // a filter matches all or any of its terms
+    let filter_keys = if terms.iter().any(|term| term.or) {
+        or_chain(keys)
+    } else {
+        keys.concat()
+    };
+
+    let mut rule = Rule {
+        name: filter.name.clone(),
+        filter: filter_keys.join(" "),
+        enable: filter.enabled,
+        ..Default::default()
+    };
+    let mut target = None;
+    let mut flags = Vec::new();
+    for (action, value) in &filter.actions {
+        match action.as_str() {
+            "Move to folder" => target = Some(folder_from_uri(value)?),
+            "Delete" => {
+                notes.push("delete moves to Trash".to_string());
+                target = Some("Trash".to_string());
+            }
+            "Mark read" => flags.push("\\Seen".to_string()),
+            "Mark flagged" => flags.push("\\Flagged".to_string()),
+            "AddTag" => flags.push(value.clone()),
+            "Stop execution" => {}
+            action => notes.push(format!("action {} dropped", action)),
+        }
+    }
+    rule.target = target.ok_or("no move to folder action".to_string())?;
+    if !flags.is_empty() {
+        rule.flags = Some(flags);
+    }
+    Ok(rule)
+}
+
+// rules for INBOX equivalent to Thunderbird filters, along with what could not be imported as is
+pub fn import_thunderbird(content: &str) -> (RulesSet, Vec<(String, String)>) {
+    let mut rules = Vec::new();
+    let mut reports = Vec::new();
+    for filter in parse_filters(content) {
+        let mut notes = Vec::new();
+        match filter_to_rule(&filter, &mut notes) {
+            Ok(rule) => rules.push(rule),
+            Err(reason) => reports.push((filter.name.clone(), format!("not imported: {}", reason))),
+        }
+        for note in notes {
+            reports.push((filter.name.clone(), note));
+        }
+    }
+    let rules_set = RulesSet {
+        folders: vec![FolderRule {
+            folder: "INBOX".to_string(),
+            rules,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    (rules_set, reports)
+}
+
+pub fn read_filters_file(path: &str) -> Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("Failed to open file: {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (filter, target) of each rule, or (filter name, report)
+    type Pairs = Vec<(String, String)>;
+
+    // filters and reports of the rules imported from a file
+    fn import(content: &str) -> (Pairs, Pairs) {
+        let (rules_set, reports) = import_thunderbird(content);
+        let rules = rules_set.folders[0]
+            .rules
+            .iter()
+            .map(|rule| (rule.filter.clone(), rule.target.clone()))
+            .collect();
+        (rules, reports)
+    }
+
+    fn filter(name: &str, condition: &str) -> String {
+        format!(
+            "version=\"9\"\nlogging=\"no\"\nname=\"{}\"\nenabled=\"yes\"\ntype=\"17\"\naction=\"Move to folder\"\nactionValue=\"imap://me%40example.com@imap.example.com/Lists%2FRust\"\ncondition=\"{}\"\n",
+            name, condition
+        )
+    }
+
+    fn pair(first: &str, second: &str) -> (String, String) {
+        (first.to_string(), second.to_string())
+    }
+
+    #[test]
+    fn values_keep_escaped_trailing_quotes() {
+        let filters = parse_filters("name=\"say \\\"hi\\\"\"\nenabled=\"yes\"\n");
+        assert_eq!(filters.len(), 1);
+        assert_eq!(filters[0].name, "say \"hi\"");
+        assert!(filters[0].enabled);
+    }
+
+    #[test]
+    fn quoted_fields_keep_commas_and_parenthesis() {
+        let terms =
+            parse_condition(r#"AND (subject,contains,"a, (b)") OR ("X-Spam",is,yes)"#).unwrap();
+        assert_eq!(
+            terms,
+            vec![
+                Term {
+                    or: false,
+                    attribute: "subject".to_string(),
+                    quoted: false,
+                    operator: "contains".to_string(),
+                    value: "a, (b)".to_string(),
+                },
+                Term {
+                    or: true,
+                    attribute: "x-spam".to_string(),
+                    quoted: true,
+                    operator: "is".to_string(),
+                    value: "yes".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_conditions_are_errors() {
+        assert!(parse_condition("AND (subject,contains,x").is_err());
+        assert!(parse_condition("AND subject,contains,x)").is_err());
+        assert!(parse_condition("NOT (subject,contains,x)").is_err());
+    }
+
+    #[test]
+    fn terms_import_as_search_keys() {
+        let (rules, reports) = import(&filter(
+            "rust",
+            r#"AND (from,contains,a@b.c) AND (size,is greater than,10)"#,
+        ));
+        assert_eq!(
+            rules,
+            vec![pair("FROM \"a@b.c\" LARGER 10240", "Lists/Rust")]
+        );
+        assert!(reports.is_empty());
+
+        let (rules, _) = import(&filter("any", "OR (to,contains,x) OR (cc,contains,y)"));
+        assert_eq!(rules, vec![pair("OR TO \"x\" CC \"y\"", "Lists/Rust")]);
+    }
+
+    #[test]
+    fn quoted_attributes_are_custom_headers() {
+        let (rules, reports) = import(&filter(
+            "custom",
+            r#"AND (\"Sender\",contains,list@b.c) AND (\"X\",doesn't contain,1)"#,
+        ));
+        assert_eq!(
+            rules,
+            vec![pair(
+                "HEADER sender \"list@b.c\" NOT HEADER x \"1\"",
+                "Lists/Rust"
+            )]
+        );
+        assert!(reports.is_empty());
+    }
+
+    #[test]
+    fn unsupported_filters_are_reported() {
+        let (rules, reports) = import(&filter("junk", "AND (junk status,is,2)"));
+        assert!(rules.is_empty());
+        assert_eq!(
+            reports,
+            vec![pair(
+                "junk",
+                "not imported: unsupported condition on junk status"
+            )]
+        );
+
+        let (_, reports) = import(&filter("begins", "AND (subject,begins with,news)"));
+        assert_eq!(
+            reports,
+            vec![pair("begins", "subject begins with searched as contains")]
+        );
+    }
+}
@@ -0,0 +1,495 @@
+use crate::imap_tools::{address, decode_header, header_value};
+use crate::lists::list_id;
+use crate::rules::{Rule, RulesSet};
+use crate::store::MailStore;
+use crate::suggest::{subject_prefix, Cluster, ClusterKind};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+// a terminal interface to browse folders, and write rules from the messages found there
+
+// messages listed for a folder, the latest ones
+const MAX_MESSAGES: usize = 200;
+
+const FIELDS: [&str; 4] = ["name", "filter", "list", "target"];
+
+// what the message list shows of a message
+#[derive(Debug, Clone)]
+pub(crate) struct Envelope {
+    pub uid: u32,
+    pub date: String,
+    pub from: String,
+    pub subject: String,
+    pub list_id: Option<String>,
+}
+
+// dates shown the same way whatever the sender wrote
+fn short_date(date: &str) -> String {
+    match chrono::DateTime::parse_from_rfc2822(date.trim()) {
+        Ok(date) => date.format("%Y-%m-%d %H:%M").to_string(),
+        Err(_) => date.chars().take(16).collect(),
+    }
+}
+
+// the latest messages of a folder, latest first
+pub(crate) fn fetch_envelopes(
+    store: &mut dyn MailStore,
+    folder: &str,
+) -> anyhow::Result<Vec<Envelope>> {
+    store.open_folder(folder)?;
+    let uids = store.search_uids("ALL")?;
+    let latest = &uids[uids.len().saturating_sub(MAX_MESSAGES)..];
+    let headers = store.fetch_headers(latest, &["Date", "From", "Subject", "List-Id"])?;
+    let mut envelopes: Vec<Envelope> = headers
+        .iter()
+        .map(|(uid, header)| {
+            let field = |name: &str| {
+                header_value(header, name)
+                    .map(|value| decode_header(value.as_bytes()))
+                    .unwrap_or_default()
+            };
+            Envelope {
+                uid: *uid,
+                date: short_date(&field("Date")),
+                from: field("From"),
+                subject: field("Subject"),
+                list_id: header_value(header, "List-Id").map(|value| list_id(&value)),
+            }
+        })
+        .collect();
+    envelopes.sort_by_key(|envelope| std::cmp::Reverse(envelope.uid));
+    Ok(envelopes)
+}
+
+// rule prefilled from a message, by its list, its sender or its subject
+pub(crate) fn rule_from(envelope: &Envelope, kind: ClusterKind) -> Option<Rule> {
+    let value = match kind {
+        ClusterKind::List => envelope.list_id.clone()?,
+        ClusterKind::Sender => address(&envelope.from),
+        ClusterKind::Domain => address(&envelope.from).split_once('@')?.1.to_string(),
+        ClusterKind::Subject => subject_prefix(&envelope.subject)?,
+    };
+    let cluster = Cluster {
+        kind,
+        value,
+        count: 1,
+        examples: Vec::new(),
+    };
+    Some(Rule {
+        // written after looking at what it matches
+        enable: true,
+        ..cluster.to_rule()
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Focus {
+    Folders,
+    Messages,
+    Editor,
+}
+
+// rule being written, field by field, for the folder it was started from
+struct Editor {
+    folder: String,
+    fields: [String; 4],
+    field: usize,
+}
+
+impl Editor {
+    fn new(folder: &str, rule: &Rule) -> Self {
+        Editor {
+            folder: folder.to_string(),
+            fields: [
+                rule.name.clone(),
+                rule.filter.clone(),
+                rule.list.clone().unwrap_or_default(),
+                rule.target.clone(),
+            ],
+            field: 0,
+        }
+    }
+
+    fn rule(&self) -> Rule {
+        let [name, filter, list, target] =
+            self.fields.clone().map(|field| field.trim().to_string());
+        Rule {
+            name,
+            filter,
+            list: (!list.is_empty()).then_some(list),
+            target,
+            enable: true,
+            ..Default::default()
+        }
+    }
+}
+
+struct App {
+    rules_set: RulesSet,
+    rules_path: String,
+    folders: Vec<String>,
+    delimiter: String,
+    folder_state: ListState,
+    // folder whose messages are listed
+    opened: Option<String>,
+    messages: Vec<Envelope>,
+    message_state: ListState,
+    focus: Focus,
+    editor: Option<Editor>,
+    // uids of the listed folder matched by the rule being written
+    preview: Vec<u32>,
+    status: String,
+    quit: bool,
+}
+
+impl App {
+    fn new(rules_set: RulesSet, rules_path: &str, folders: Vec<String>, delimiter: &str) -> Self {
+        let mut folder_state = ListState::default();
+        folder_state.select((!folders.is_empty()).then_some(0));
+        App {
+            rules_set,
+            rules_path: rules_path.to_string(),
+            folders,
+            delimiter: delimiter.to_string(),
+            folder_state,
+            opened: None,
+            messages: Vec::new(),
+            message_state: ListState::default(),
+            focus: Focus::Folders,
+            editor: None,
+            preview: Vec::new(),
+            status: "Enter opens a folder, Tab switches panes, q quits".to_string(),
+            quit: false,
+        }
+    }
+
+    fn open_folder(&mut self, store: &mut dyn MailStore) {
+        let Some(folder) = self
+            .folder_state
+            .selected()
+            .and_then(|index| self.folders.get(index))
+            .cloned()
+        else {
+            return;
+        };
+        match fetch_envelopes(store, &folder) {
+            Ok(messages) => {
+                self.status = format!(
+                    "{}: {} latest messages, r creates a rule (f from, l list, s subject)",
+                    folder,
+                    messages.len()
+                );
+                self.message_state
+                    .select((!messages.is_empty()).then_some(0));
+                self.messages = messages;
+                self.opened = Some(folder);
+                self.preview.clear();
+                self.focus = Focus::Messages;
+            }
+            Err(error) => self.status = format!("cannot open {}: {:#}", folder, error),
+        }
+    }
+
+    fn create_rule(&mut self, kind: Option<ClusterKind>) {
+        let (Some(folder), Some(envelope)) = (
+            self.opened.clone(),
+            self.message_state
+                .selected()
+                .and_then(|index| self.messages.get(index)),
+        ) else {
+            return;
+        };
+        // by default a list is the best guess, then the sender
+        let kind = kind.unwrap_or(if envelope.list_id.is_some() {
+            ClusterKind::List
+        } else {
+            ClusterKind::Sender
+        });
+        match rule_from(envelope, kind) {
+            Some(rule) => {
+                self.editor = Some(Editor::new(&folder, &rule));
+                self.focus = Focus::Editor;
+                self.preview.clear();
+                self.status =
+                    "Tab next field, Ctrl-p previews, Ctrl-s saves, Esc cancels".to_string();
+            }
+            None => self.status = format!("this message has no {:?} to build a rule", kind),
+        }
+    }
+
+    fn preview(&mut self, store: &mut dyn MailStore) {
+        let Some(editor) = &self.editor else {
+            return;
+        };
+        let rule = editor.rule();
+        let query = rule.search_filter();
+        if query.is_empty() {
+            self.status = "a rule needs a filter or a list".to_string();
+            return;
+        }
+        let matched = store
+            .open_folder(&editor.folder)
+            .and_then(|_| store.search_uids(&query));
+        match matched {
+            Ok(uids) => {
+                let listed = self
+                    .messages
+                    .iter()
+                    .filter(|message| uids.contains(&message.uid))
+                    .count();
+                self.status = format!(
+                    "matches {} messages of {}, {} of them listed and marked with *",
+                    uids.len(),
+                    editor.folder,
+                    listed
+                );
+                self.preview = uids;
+            }
+            Err(error) => self.status = format!("cannot search {}: {:#}", query, error),
+        }
+    }
+
+    fn save(&mut self) {
+        let Some(editor) = &self.editor else {
+            return;
+        };
+        let rule = editor.rule();
+        let problem = if rule.name.is_empty() {
+            Some("a rule needs a name".to_string())
+        } else if rule.target.is_empty() {
+            Some("a rule needs a target".to_string())
+        } else if rule.search_filter().is_empty() {
+            Some("a rule needs a filter or a list".to_string())
+        } else if self.rules_set.find_rule(&rule.name).is_some() {
+            Some(format!("a rule named {} already exists", rule.name))
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            self.status = problem;
+            return;
+        }
+        let folder = editor.folder.clone();
+        match RulesSet::add_rule_to_file(&self.rules_path, &folder, rule.clone()) {
+            Ok(()) => {
+                self.status = format!(
+                    "rule {} saved to {}, for {}",
+                    rule.name, self.rules_path, folder
+                );
+                // known from now on, so that its name is not taken twice
+                self.rules_set.folders.push(crate::rules::FolderRule {
+                    folder,
+                    rules: vec![rule],
+                    ..Default::default()
+                });
+                self.editor = None;
+                self.focus = Focus::Messages;
+            }
+            Err(error) => self.status = format!("{:#}", error),
+        }
+    }
+
+    fn handle_key(&mut self, store: &mut dyn MailStore, key: KeyEvent) {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        if self.focus == Focus::Editor {
+            let Some(editor) = self.editor.as_mut() else {
+                self.focus = Focus::Messages;
+                return;
+            };
+            match key.code {
+                KeyCode::Esc => {
+                    self.editor = None;
+                    self.preview.clear();
+                    self.focus = Focus::Messages;
+                }
+                KeyCode::Char('p') if control => self.preview(store),
+                KeyCode::Char('s') if control => self.save(),
+                KeyCode::Tab | KeyCode::Down | KeyCode::Enter => {
+                    editor.field = (editor.field + 1) % FIELDS.len()
+                }
+                KeyCode::BackTab | KeyCode::Up => {
+                    editor.field = (editor.field + FIELDS.len() - 1) % FIELDS.len()
+                }
+                KeyCode::Backspace => {
+                    editor.fields[editor.field].pop();
+                }
+                KeyCode::Char(c) if !control => editor.fields[editor.field].push(c),
+                _ => {}
+            }
+            return;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Tab | KeyCode::Left | KeyCode::Right => {
+                self.focus = match self.focus {
+                    Focus::Folders if self.opened.is_some() => Focus::Messages,
+                    _ => Focus::Folders,
+                }
+            }
+            KeyCode::Down | KeyCode::Char('j') => self.state().select_next(),
+            KeyCode::Up | KeyCode::Char('k') => self.state().select_previous(),
+            KeyCode::Enter if self.focus == Focus::Folders => self.open_folder(store),
+            KeyCode::Char('r') if self.focus == Focus::Messages => self.create_rule(None),
+            KeyCode::Char('f') if self.focus == Focus::Messages => {
+                self.create_rule(Some(ClusterKind::Sender))
+            }
+            KeyCode::Char('l') if self.focus == Focus::Messages => {
+                self.create_rule(Some(ClusterKind::List))
+            }
+            KeyCode::Char('s') if self.focus == Focus::Messages => {
+                self.create_rule(Some(ClusterKind::Subject))
+            }
+            _ => {}
+        }
+    }
+
+    fn state(&mut self) -> &mut ListState {
+        match self.focus {
+            Focus::Folders => &mut self.folder_state,
+            _ => &mut self.message_state,
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)])
+                .areas(main);
+        let highlight = Style::default().add_modifier(Modifier::REVERSED);
+        let title = |name: &str, focused: bool| {
+            if focused {
+                Block::bordered().title(format!("[{}]", name))
+            } else {
+                Block::bordered().title(name.to_string())
+            }
+        };
+
+        // folders as a tree, those with rules marked with +
+        let with_rules = self.rules_set.list_folders();
+        let folders: Vec<ListItem> = self
+            .folders
+            .iter()
+            .map(|folder| {
+                let depth = folder.matches(self.delimiter.as_str()).count();
+                let name = folder
+                    .rsplit(self.delimiter.as_str())
+                    .next()
+                    .unwrap_or(folder);
+                let mark = if with_rules.contains(folder) {
+                    " +"
+                } else {
+                    ""
+                };
+                ListItem::new(format!("{}{}{}", "  ".repeat(depth), name, mark))
+            })
+            .collect();
+        frame.render_stateful_widget(
+            List::new(folders)
+                .block(title("folders", self.focus == Focus::Folders))
+                .highlight_style(highlight),
+            left,
+            &mut self.folder_state,
+        );
+
+        let (messages_area, editor_area) = if self.editor.is_some() {
+            let [messages, editor] =
+                Layout::vertical([Constraint::Min(3), Constraint::Length(7)]).areas(right);
+            (messages, Some(editor))
+        } else {
+            (right, None)
+        };
+        let messages: Vec<ListItem> = self
+            .messages
+            .iter()
+            .map(|message| {
+                let mark = if self.preview.contains(&message.uid) {
+                    "*"
+                } else {
+                    " "
+                };
+                let from: String = message.from.chars().take(28).collect();
+                ListItem::new(format!(
+                    "{} {:<16} {:<28} {}",
+                    mark, message.date, from, message.subject
+                ))
+            })
+            .collect();
+        let opened = self.opened.clone().unwrap_or_default();
+        frame.render_stateful_widget(
+            List::new(messages)
+                .block(title(
+                    &format!("messages {}", opened),
+                    self.focus == Focus::Messages,
+                ))
+                .highlight_style(highlight),
+            messages_area,
+            &mut self.message_state,
+        );
+
+        if let (Some(editor), Some(area)) = (&self.editor, editor_area) {
+            let lines: Vec<Line> = FIELDS
+                .iter()
+                .zip(editor.fields.iter())
+                .enumerate()
+                .map(|(index, (name, value))| {
+                    let style = if index == editor.field {
+                        highlight
+                    } else {
+                        Style::default()
+                    };
+                    Line::from(vec![
+                        Span::raw(format!("{:>7}: ", name)),
+                        Span::styled(value.clone(), style),
+                    ])
+                })
+                .collect();
+            frame.render_widget(
+                Paragraph::new(lines)
+                    .block(title(&format!("new rule for {}", editor.folder), true)),
+                area,
+            );
+        }
+
+        frame.render_widget(
+            Paragraph::new(self.status.clone()).block(Block::bordered()),
+            status,
+        );
+    }
+}
+
+fn event_loop(
+    terminal: &mut DefaultTerminal,
+    app: &mut App,
+    store: &mut dyn MailStore,
+) -> anyhow::Result<()> {
+    while !app.quit {
+        terminal.draw(|frame| app.draw(frame))?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press {
+                app.handle_key(store, key);
+            }
+        }
+    }
+    Ok(())
+}
+
+// browse folders of a store, rules written are added to the rules file
+pub fn run(
+    store: &mut dyn MailStore,
+    folders: Vec<String>,
+    delimiter: &str,
+    rules_set: RulesSet,
+    rules_path: &str,
+) -> anyhow::Result<()> {
+    let mut app = App::new(rules_set, rules_path, folders, delimiter);
+    let mut terminal = ratatui::try_init()?;
+    let result = event_loop(&mut terminal, &mut app, store);
+    // the terminal is restored even when something failed
+    ratatui::restore();
+    result
+}
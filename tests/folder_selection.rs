@@ -0,0 +1,260 @@
+use chrono::{DateTime, FixedOffset};
+use imap_sorter::{
+    FolderSelection, HeaderAndText, MailStore, RulesSet, RunOptions, Sorter, StoredMessage,
+};
+use std::collections::BTreeMap;
+
+// rules of INBOX move vendor messages, rules of Lists/* move rust messages
+const RULES: &str = "
+folders:
+  - folder: INBOX
+    rules:
+      - name: vendor
+        filter: FROM vendor@example.com
+        target: Vendors
+        enable: true
+  - folder: Lists/rust
+    rules:
+      - name: rust
+        filter: FROM rust@example.com
+        target: Rust
+        enable: true
+  - folder: Lists/python
+    rules:
+      - name: python
+        filter: FROM python@example.com
+        target: Python
+        enable: true
+";
+
+fn load_rules(name: &str) -> RulesSet {
+    let path =
+        std::env::temp_dir().join(format!("imap_sorter_{}_{}.yaml", name, std::process::id()));
+    std::fs::write(&path, RULES).unwrap();
+    let rules_set = RulesSet::load(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    rules_set
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+fn pairs(values: &[(&str, &str)]) -> Vec<(String, String)> {
+    values
+        .iter()
+        .map(|(rules_folder, folder)| (rules_folder.to_string(), folder.to_string()))
+        .collect()
+}
+
+// folders holding messages as (uid, sender), searched by FROM only
+#[derive(Default)]
+struct MemoryStore {
+    folders: BTreeMap<String, Vec<(u32, String)>>,
+    opened: String,
+}
+
+impl MemoryStore {
+    fn with(folder: &str, senders: &[&str]) -> Self {
+        let mut store = MemoryStore::default();
+        store.add(folder, senders);
+        store
+    }
+
+    fn add(&mut self, folder: &str, senders: &[&str]) {
+        let messages = self.folders.entry(folder.to_string()).or_default();
+        for sender in senders {
+            let uid = messages.len() as u32 + 1;
+            messages.push((uid, sender.to_string()));
+        }
+    }
+
+    fn senders(&self, folder: &str) -> Vec<String> {
+        self.folders
+            .get(folder)
+            .map(|messages| messages.iter().map(|(_, from)| from.clone()).collect())
+            .unwrap_or_default()
+    }
+}
+
+impl MailStore for MemoryStore {
+    fn open_folder(&mut self, folder: &str) -> anyhow::Result<()> {
+        self.opened = folder.to_string();
+        Ok(())
+    }
+
+    fn search_uids(&mut self, query: &str) -> anyhow::Result<Vec<u32>> {
+        let from = query.strip_prefix("FROM ").unwrap_or_default();
+        Ok(self
+            .folders
+            .get(&self.opened)
+            .map(|messages| {
+                messages
+                    .iter()
+                    .filter(|(_, sender)| sender == from)
+                    .map(|(uid, _)| *uid)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn fetch_headers(
+        &mut self,
+        _uids: &[u32],
+        _fields: &[&str],
+    ) -> anyhow::Result<Vec<(u32, Vec<u8>)>> {
+        Ok(Vec::new())
+    }
+
+    fn fetch_headers_and_text(
+        &mut self,
+        _uids: &[u32],
+        _fields: &[&str],
+        _text_limit: usize,
+    ) -> anyhow::Result<Vec<HeaderAndText>> {
+        Ok(Vec::new())
+    }
+
+    fn fetch_dates(&mut self, _uids: &[u32]) -> anyhow::Result<Vec<(u32, DateTime<FixedOffset>)>> {
+        Ok(Vec::new())
+    }
+
+    fn fetch_messages(&mut self, _uids: &[u32]) -> anyhow::Result<Vec<StoredMessage>> {
+        Ok(Vec::new())
+    }
+
+    fn add_flags(&mut self, _uids: &[u32], _flags: &[String]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn move_messages(&mut self, uids: &[u32], target: &str) -> anyhow::Result<()> {
+        let messages = self.folders.entry(self.opened.clone()).or_default();
+        let (moved, kept): (Vec<_>, Vec<_>) =
+            messages.drain(..).partition(|(uid, _)| uids.contains(uid));
+        *messages = kept;
+        let senders: Vec<&str> = moved.iter().map(|(_, from)| from.as_str()).collect();
+        self.add(target, &senders);
+        Ok(())
+    }
+
+    fn delete_messages(&mut self, _uids: &[u32]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn can_delete(&mut self) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+
+    fn threads(&mut self) -> anyhow::Result<Option<Vec<Vec<u32>>>> {
+        Ok(None)
+    }
+}
+
+#[test]
+fn all_sorts_each_folder_with_its_rules() {
+    let rules_set = load_rules("all");
+    assert_eq!(
+        FolderSelection::All.resolve(&rules_set, &[], "/"),
+        pairs(&[
+            ("INBOX", "INBOX"),
+            ("Lists/rust", "Lists/rust"),
+            ("Lists/python", "Lists/python")
+        ])
+    );
+}
+
+#[test]
+fn only_folder_takes_names_and_patterns_among_folders_with_rules() {
+    let rules_set = load_rules("only");
+    let selection = FolderSelection::Only(strings(&["inbox", "Lists/%", "Unknown"]));
+    assert!(selection.has_patterns());
+    assert_eq!(
+        selection.resolve(&rules_set, &[], "/"),
+        pairs(&[
+            ("INBOX", "INBOX"),
+            ("Lists/rust", "Lists/rust"),
+            ("Lists/python", "Lists/python")
+        ])
+    );
+    assert!(!FolderSelection::Only(strings(&["INBOX"])).has_patterns());
+}
+
+#[test]
+fn rules_of_apply_on_each_folder() {
+    let rules_set = load_rules("rules_of");
+    let mailboxes = strings(&["INBOX", "Archive/2023", "Archive/2024", "Sent"]);
+    let selection = FolderSelection::RulesOf {
+        rules_of: strings(&["INBOX", "Lists/rust"]),
+        on: strings(&["Archive/*", "Old"]),
+    };
+    assert_eq!(
+        selection.resolve(&rules_set, &mailboxes, "/"),
+        pairs(&[
+            ("INBOX", "Archive/2023"),
+            ("INBOX", "Archive/2024"),
+            ("INBOX", "Old"),
+            ("Lists/rust", "Archive/2023"),
+            ("Lists/rust", "Archive/2024"),
+            ("Lists/rust", "Old")
+        ])
+    );
+}
+
+#[test]
+fn rules_of_a_folder_without_rules_apply_nothing() {
+    let rules_set = load_rules("no_rules");
+    let selection = FolderSelection::RulesOf {
+        rules_of: strings(&["Sent"]),
+        on: strings(&["INBOX"]),
+    };
+    assert!(selection.resolve(&rules_set, &[], "/").is_empty());
+}
+
+#[test]
+fn sorter_moves_with_the_rules_of_another_folder() {
+    let rules_set = load_rules("sorter_rules_of");
+    let options = RunOptions::default();
+    let mut store = MemoryStore::with(
+        "Archive",
+        &[
+            "vendor@example.com",
+            "rust@example.com",
+            "friend@example.com",
+        ],
+    );
+    let selection = FolderSelection::RulesOf {
+        rules_of: strings(&["INBOX"]),
+        on: strings(&["Archive"]),
+    };
+    let folders = selection.resolve(&rules_set, &[], "/");
+    let report = Sorter::new(&rules_set, &options).run_on(&mut store, &folders);
+
+    assert_eq!(report.moved(), 1);
+    assert_eq!(report.folders[0].folder, "Archive");
+    assert_eq!(store.senders("Vendors"), strings(&["vendor@example.com"]));
+    // the rust rule belongs to Lists/rust, not to INBOX
+    assert_eq!(
+        store.senders("Archive"),
+        strings(&["rust@example.com", "friend@example.com"])
+    );
+}
+
+#[test]
+fn sorter_moves_only_the_selected_folders() {
+    let rules_set = load_rules("sorter_only");
+    let options = RunOptions::default();
+    let mut store = MemoryStore::with("INBOX", &["vendor@example.com"]);
+    store.add("Lists/rust", &["rust@example.com"]);
+    store.add("Lists/python", &["python@example.com"]);
+    let selection = FolderSelection::Only(strings(&["Lists/rust"]));
+    let folders = selection.resolve(&rules_set, &[], "/");
+    let report = Sorter::new(&rules_set, &options).run_on(&mut store, &folders);
+
+    assert_eq!(report.moved(), 1);
+    assert_eq!(store.senders("Rust"), strings(&["rust@example.com"]));
+    assert_eq!(store.senders("INBOX"), strings(&["vendor@example.com"]));
+    assert_eq!(
+        store.senders("Lists/python"),
+        strings(&["python@example.com"])
+    );
+}